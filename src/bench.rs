@@ -0,0 +1,90 @@
+// Copyright (C) 2023-2024  DCsunset
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::{
+  fs, thread,
+  os::unix::fs::FileExt,
+  path::PathBuf,
+  sync::atomic::{AtomicU64, Ordering},
+  time::Instant
+};
+use anyhow::{Result, Context};
+use clap::Parser;
+use rangefs::stats::LatencyHistogram;
+use crate::parse_size_arg;
+
+/// Measure read throughput of a file (usually a mapped file in a mount)
+#[derive(Parser)]
+#[command(name = "rangefs bench")]
+pub struct BenchArgs {
+  /// Size of each read
+  #[arg(long, value_name = "BYTES", value_parser = parse_size_arg, default_value = "128K")]
+  block_size: u64,
+
+  /// Number of concurrent readers (reading interleaved blocks)
+  #[arg(long, default_value_t = 1)]
+  concurrency: u64,
+
+  /// Number of times to read the whole file
+  #[arg(long, default_value_t = 1)]
+  passes: u32,
+
+  /// File to read
+  file: PathBuf
+}
+
+/// Read the file and report throughput and latency percentiles
+pub fn run(args: BenchArgs) -> Result<()> {
+  let file = fs::File::open(&args.file)
+    .with_context(|| format!("error opening {:?}", args.file))?;
+  let size = file.metadata()?.len();
+  let block_size = args.block_size.max(1);
+  let concurrency = args.concurrency.max(1);
+  let latency = LatencyHistogram::new();
+  let bytes = AtomicU64::new(0);
+
+  let start = Instant::now();
+  thread::scope(|s| {
+    let readers: Vec<_> = (0..concurrency).map(|i| {
+      let (file, latency, bytes) = (&file, &latency, &bytes);
+      s.spawn(move || {
+        let mut buf = vec![0; block_size as usize];
+        for _ in 0..args.passes {
+          let mut offset = i * block_size;
+          while offset < size {
+            let t = Instant::now();
+            let n = file.read_at(&mut buf, offset)?;
+            latency.record(t.elapsed());
+            bytes.fetch_add(n as u64, Ordering::Relaxed);
+            offset += concurrency * block_size;
+          }
+        }
+        Ok::<_, std::io::Error>(())
+      })
+    }).collect();
+    readers.into_iter().try_for_each(|r| r.join().unwrap_or_else(|e| std::panic::resume_unwind(e)))
+  }).with_context(|| format!("error reading {:?}", args.file))?;
+  let elapsed = start.elapsed().as_secs_f64();
+
+  let bytes = bytes.load(Ordering::Relaxed);
+  println!(
+    "read {} bytes in {:.3}s: {:.1} MB/s, latency {}",
+    bytes,
+    elapsed,
+    bytes as f64 / 1e6 / elapsed.max(f64::EPSILON),
+    latency.summary()
+  );
+  Ok(())
+}
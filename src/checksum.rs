@@ -0,0 +1,102 @@
+// Copyright (C) 2023-2024  DCsunset
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use clap::ValueEnum;
+use sha2::{Digest, Sha256};
+
+/// Algorithm of checksum sidecar files
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum)]
+pub enum Checksum {
+  Sha256
+}
+
+impl Checksum {
+  /// Extension of sidecar files (also the name of the sha*sum tool)
+  pub fn extension(&self) -> &'static str {
+    match self {
+      Checksum::Sha256 => "sha256"
+    }
+  }
+
+  /// Length of the digest in hex
+  pub fn hex_len(&self) -> usize {
+    match self {
+      Checksum::Sha256 => 64
+    }
+  }
+
+  pub fn hasher(&self) -> Hasher {
+    match self {
+      Checksum::Sha256 => Hasher::Sha256(Sha256::new())
+    }
+  }
+}
+
+/// Incremental hasher of a checksum algorithm
+pub enum Hasher {
+  Sha256(Sha256)
+}
+
+impl Hasher {
+  pub fn update(&mut self, data: &[u8]) {
+    match self {
+      Hasher::Sha256(h) => h.update(data)
+    }
+  }
+
+  /// Digest in lowercase hex
+  pub fn finish(self) -> String {
+    let digest = match self {
+      Hasher::Sha256(h) => h.finalize()
+    };
+    digest.iter().map(|b| format!("{:02x}", b)).collect()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn sha256(chunks: &[&[u8]]) -> String {
+    let mut hasher = Checksum::Sha256.hasher();
+    for chunk in chunks {
+      hasher.update(chunk);
+    }
+    hasher.finish()
+  }
+
+  #[test]
+  fn sha256_known_answers() {
+    // test vectors of FIPS 180-4 (examples of NIST CSRC)
+    assert_eq!(sha256(&[b""]), "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855");
+    assert_eq!(sha256(&[b"abc"]), "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad");
+    assert_eq!(
+      sha256(&[b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq"]),
+      "248d6a61d20638b8e5c026930c3e6039a33ce45964ff2167f6ecedd419db06c1"
+    );
+    assert_eq!(
+      sha256(&[&[b'a'; 1_000_000]]),
+      "cdc76e5c9914fb9281a1c7e284d73e67f1809a48a497200e046d39ccc7112cd0"
+    );
+  }
+
+  #[test]
+  fn sha256_chunked() {
+    let data: Vec<u8> = (0..1000u32).map(|i| i as u8).collect();
+    let whole = sha256(&[&data]);
+    assert_eq!(sha256(&[&data[..1], &data[1..63], &data[63..64], &data[64..500], &data[500..]]), whole);
+    assert_eq!(whole.len(), Checksum::Sha256.hex_len());
+  }
+}
@@ -0,0 +1,680 @@
+// Copyright (C) 2023-2024  DCsunset
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::{fmt, error::Error, ffi::{CStr, CString, c_char, c_int}, mem::MaybeUninit, num::ParseIntError, ptr, path::PathBuf, str::FromStr, time::{Duration, SystemTime, UNIX_EPOCH}};
+use clap::ValueEnum;
+use fuser::FileType;
+use regex::bytes::Regex;
+use crate::{metadata::{InodeConfig, Stripe, KeySource, encode_dev}, synthetic::Synthetic, transform};
+
+/// Policy for keys repeated in a config
+#[derive(Clone, Copy, Default, ValueEnum)]
+pub enum DuplicateKey {
+  /// Reject the config
+  #[default]
+  Error,
+  /// Use the last value
+  LastWins
+}
+
+/// Error parsing mapping config
+#[derive(Debug)]
+pub enum ConfigError {
+  /// Unknown option key
+  UnknownKey(String),
+  /// Option without value
+  MissingValue(String),
+  /// Option with invalid number
+  BadNumber(String, ParseIntError),
+  /// Option with invalid value
+  BadValue(String),
+  /// Options that can't be used together
+  Conflict(String),
+  /// Key repeated in a config
+  DuplicateKey(String)
+}
+
+impl fmt::Display for ConfigError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      ConfigError::UnknownKey(opt) | ConfigError::MissingValue(opt) => write!(f, "invalid option: {}", opt),
+      ConfigError::BadNumber(_, err) => write!(f, "{}", err),
+      ConfigError::BadValue(msg) | ConfigError::Conflict(msg) => write!(f, "{}", msg),
+      ConfigError::DuplicateKey(key) => write!(f, "duplicate option: {}", key)
+    }
+  }
+}
+
+impl Error for ConfigError {
+  fn source(&self) -> Option<&(dyn Error + 'static)> {
+    match self {
+      ConfigError::BadNumber(_, err) => Some(err),
+      _ => None
+    }
+  }
+}
+
+impl ConfigError {
+  /// Invalid value of option
+  pub fn invalid(opt: &str) -> Self {
+    ConfigError::BadValue(format!("invalid option: {}", opt))
+  }
+}
+
+/// Parse number in option value
+pub fn parse_number<T: FromStr<Err = ParseIntError>>(opt: &str, value: &str) -> Result<T, ConfigError> {
+  value.parse().map_err(|err| ConfigError::BadNumber(opt.into(), err))
+}
+
+/// Parse size in option value with optional binary suffix (K, M, G or T)
+pub fn parse_size(opt: &str, value: &str) -> Result<u64, ConfigError> {
+  let (num, shift) = match value.as_bytes().last() {
+    Some(b'K' | b'k') => (&value[..value.len() - 1], 10),
+    Some(b'M' | b'm') => (&value[..value.len() - 1], 20),
+    Some(b'G' | b'g') => (&value[..value.len() - 1], 30),
+    Some(b'T' | b't') => (&value[..value.len() - 1], 40),
+    _ => (value, 0)
+  };
+  let n: u64 = parse_number(opt, num)?;
+  n.checked_mul(1 << shift)
+    .ok_or_else(|| ConfigError::BadValue(format!("size overflows: {}", value)))
+}
+
+/// Max buffer size for strings of a user or group entry
+const MAX_ENTRY_BUF_SIZE: usize = 1 << 20;
+
+/// Reentrant lookup of a user or group entry by name (e.g. getpwnam_r)
+type LookupFn<T> = unsafe extern "C" fn(*const c_char, *mut T, *mut c_char, libc::size_t, *mut *mut T) -> c_int;
+
+/// Look up a user or group entry by name with a caller-owned buffer
+/// (safe to call from any thread) and get its id
+fn lookup_id<T>(name: &CStr, lookup: LookupFn<T>, id: impl Fn(&T) -> u32) -> Option<u32> {
+  let mut buf = vec![0 as c_char; 1024];
+  loop {
+    let mut entry = MaybeUninit::<T>::uninit();
+    let mut result = ptr::null_mut();
+    let ret = unsafe { lookup(name.as_ptr(), entry.as_mut_ptr(), buf.as_mut_ptr(), buf.len(), &mut result) };
+    if ret == libc::ERANGE && buf.len() < MAX_ENTRY_BUF_SIZE {
+      buf.resize(buf.len() * 2, 0);
+      continue;
+    }
+    return (ret == 0 && !result.is_null()).then(|| id(unsafe { entry.assume_init_ref() }));
+  }
+}
+
+/// Parse uid in option value (numeric or user name)
+pub fn parse_uid(opt: &str, value: &str) -> Result<u32, ConfigError> {
+  if value.bytes().all(|b| b.is_ascii_digit()) {
+    return parse_number(opt, value);
+  }
+  let name = CString::new(value).map_err(|_| ConfigError::invalid(opt))?;
+  lookup_id(&name, libc::getpwnam_r, |pw| pw.pw_uid)
+    .ok_or_else(|| ConfigError::BadValue(format!("unknown user: {}", value)))
+}
+
+/// Parse gid in option value (numeric or group name)
+pub fn parse_gid(opt: &str, value: &str) -> Result<u32, ConfigError> {
+  if value.bytes().all(|b| b.is_ascii_digit()) {
+    return parse_number(opt, value);
+  }
+  let name = CString::new(value).map_err(|_| ConfigError::invalid(opt))?;
+  lookup_id(&name, libc::getgrnam_r, |gr| gr.gr_gid)
+    .ok_or_else(|| ConfigError::BadValue(format!("unknown group: {}", value)))
+}
+
+/// Days since 1970-01-01 of a date in proleptic Gregorian calendar
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+  let y = if month <= 2 { year - 1 } else { year };
+  let era = y.div_euclid(400);
+  let yoe = y - era * 400;
+  let doy = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+  let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+  era * 146097 + doe - 719468
+}
+
+/// Parse timestamp in option value (seconds since epoch or RFC3339)
+pub fn parse_time(opt: &str, value: &str) -> Result<SystemTime, ConfigError> {
+  let invalid = || ConfigError::BadValue(format!("invalid timestamp (epoch seconds or RFC3339): {}", value));
+  // fraction of second in nanoseconds
+  let parse_frac = |frac: &str| -> Result<u32, ConfigError> {
+    if frac.is_empty() || frac.len() > 9 || !frac.bytes().all(|b| b.is_ascii_digit()) {
+      return Err(invalid());
+    }
+    Ok(frac.parse::<u32>().map_err(|_| invalid())? * 10u32.pow(9 - frac.len() as u32))
+  };
+  let to_time = |secs: i64, nanos: u32| {
+    let time = if secs >= 0 {
+      UNIX_EPOCH.checked_add(Duration::new(secs as u64, nanos))
+    } else {
+      UNIX_EPOCH.checked_sub(Duration::from_secs(secs.unsigned_abs())).and_then(|t| t.checked_add(Duration::new(0, nanos)))
+    };
+    time.ok_or_else(invalid)
+  };
+
+  if !value.contains('-') || value.starts_with('-') && !value[1..].contains('-') {
+    // epoch seconds with optional fraction
+    let (secs, frac) = value.split_once('.').unwrap_or((value, ""));
+    let secs: i64 = parse_number(opt, secs)?;
+    let nanos = if value.contains('.') { parse_frac(frac)? } else { 0 };
+    return to_time(secs, nanos);
+  }
+
+  // RFC3339: YYYY-MM-DDTHH:MM:SS[.frac](Z|+HH:MM|-HH:MM)
+  // fields are sliced by byte position
+  let b = value.as_bytes();
+  if !value.is_ascii() || b.len() < 20 || b[4] != b'-' || b[7] != b'-' || !matches!(b[10], b'T' | b't' | b' ') || b[13] != b':' || b[16] != b':' {
+    return Err(invalid());
+  }
+  let num = |s: &str| -> Result<i64, ConfigError> {
+    if !s.bytes().all(|b| b.is_ascii_digit()) {
+      return Err(invalid());
+    }
+    s.parse().map_err(|_| invalid())
+  };
+  let (year, month, day) = (num(&value[0..4])?, num(&value[5..7])?, num(&value[8..10])?);
+  let (hour, min, sec) = (num(&value[11..13])?, num(&value[14..16])?, num(&value[17..19])?);
+  let mut rest = &value[19..];
+  let mut nanos = 0;
+  if let Some(r) = rest.strip_prefix('.') {
+    let end = r.find(|c: char| !c.is_ascii_digit()).unwrap_or(r.len());
+    nanos = parse_frac(&r[..end])?;
+    rest = &r[end..];
+  }
+  let offset = match rest {
+    "Z" | "z" => 0,
+    _ if rest.len() == 6 && rest.as_bytes()[3] == b':' => {
+      let sign = match rest.as_bytes()[0] {
+        b'+' => 1,
+        b'-' => -1,
+        _ => return Err(invalid())
+      };
+      let (oh, om) = (num(&rest[1..3])?, num(&rest[4..6])?);
+      if oh > 23 || om > 59 {
+        return Err(invalid());
+      }
+      sign * (oh * 3600 + om * 60)
+    },
+    _ => return Err(invalid())
+  };
+  let days_in_month = match month {
+    2 if year % 4 == 0 && (year % 100 != 0 || year % 400 == 0) => 29,
+    2 => 28,
+    4 | 6 | 9 | 11 => 30,
+    _ => 31
+  };
+  // allow leap second 60
+  if !(1..=12).contains(&month) || day < 1 || day > days_in_month || hour > 23 || min > 59 || sec > 60 {
+    return Err(invalid());
+  }
+  let secs = days_from_civil(year, month, day) * 86400 + hour * 3600 + min * 60 + sec - offset;
+  to_time(secs, nanos)
+}
+
+/// Pairs of config keys that can't be used together
+const CONFLICTING_KEYS: &[(&str, &str)] = &[
+  ("end", "size"),
+  ("end", "after"),
+  ("range", "size"),
+  ("range", "end"),
+  ("range", "offset"),
+  ("range", "after"),
+  ("after", "offset"),
+  ("stripe", "raid0"),
+  ("lines", "offset"),
+  ("lines", "size"),
+  ("lines", "end"),
+  ("lines", "range"),
+  ("lines", "after"),
+  ("lines", "regex_start"),
+  ("lines", "regex_end"),
+  ("regex_start", "offset"),
+  ("regex_start", "size"),
+  ("regex_start", "end"),
+  ("regex_start", "range"),
+  ("regex_start", "after"),
+  ("regex_end", "offset"),
+  ("regex_end", "size"),
+  ("regex_end", "end"),
+  ("regex_end", "range"),
+  ("regex_end", "after"),
+  ("find", "offset"),
+  ("find", "end"),
+  ("find", "range"),
+  ("find", "after"),
+  ("find", "lines"),
+  ("find", "regex_start"),
+  ("find", "regex_end"),
+  ("offset_blocks", "offset"),
+  ("offset_blocks", "range"),
+  ("offset_blocks", "after"),
+  ("offset_blocks", "lines"),
+  ("offset_blocks", "regex_start"),
+  ("offset_blocks", "regex_end"),
+  ("offset_blocks", "find"),
+  ("offset_blocks", "compute"),
+  ("size_blocks", "size"),
+  ("size_blocks", "end"),
+  ("size_blocks", "range"),
+  ("size_blocks", "lines"),
+  ("size_blocks", "regex_start"),
+  ("size_blocks", "regex_end"),
+  ("size_blocks", "compute"),
+  ("compute", "offset"),
+  ("compute", "size"),
+  ("compute", "end"),
+  ("compute", "range"),
+  ("compute", "after"),
+  ("compute", "lines"),
+  ("compute", "regex_start"),
+  ("compute", "regex_end"),
+  ("compute", "find"),
+  ("aes_ctr_key_file", "aes_ctr_key_env"),
+  ("pattern", "source")
+];
+
+/// Keys that can be used without value
+const FLAG_KEYS: &[&str] = &["preload"];
+
+/// Keys whose values may contain colons (e.g. RFC3339 timestamps)
+const COLON_VALUE_KEYS: &[&str] = &["crtime"];
+
+/// Split config string into options at colons,
+/// joining segments without "=" to the previous option if its value may contain colons
+fn split_options(config_str: &str) -> Vec<String> {
+  let mut opts: Vec<String> = Vec::new();
+  for seg in config_str.split(':') {
+    match opts.last_mut() {
+      Some(last) if !seg.contains('=') && !FLAG_KEYS.contains(&seg)
+        && last.split_once('=').is_some_and(|(key, _)| COLON_VALUE_KEYS.contains(&key)) => {
+        last.push(':');
+        last.push_str(seg);
+      },
+      _ => opts.push(seg.into())
+    };
+  }
+  opts
+}
+
+/// Keys that can't be used with synthetic data (pattern, source=zero or source=random)
+const SOURCE_KEYS: &[&str] = &["offset", "offset_blocks", "after", "range", "lines", "regex_start", "regex_end", "find", "compute", "raid0", "stripe"];
+
+/// Attributes of a mapped file and the keys setting them (defaulted if none is set)
+const ATTRIBUTE_KEYS: &[(&str, &[&str])] = &[
+  ("name", &["name"]),
+  ("source", &["source", "pattern"]),
+  ("offset", &["offset", "range", "offset_blocks", "after", "lines", "regex_start", "find", "compute"]),
+  ("size", &["size", "end", "range", "size_blocks", "lines", "regex_end", "compute"]),
+  ("uid", &["uid"]),
+  ("gid", &["gid"]),
+  ("mode", &["mode"]),
+  ("crtime", &["crtime"]),
+  ("nlink", &["nlink"])
+];
+
+/// Parsed config with the keys explicitly set
+pub struct ParsedConfig {
+  pub config: InodeConfig,
+  /// Keys in the config string in order (the last one wins if repeated)
+  pub keys: Vec<String>
+}
+
+impl ParsedConfig {
+  /// Whether a key is set explicitly
+  pub fn is_set(&self, key: &str) -> bool {
+    self.keys.iter().any(|k| k == key)
+  }
+
+  /// Attributes (name, source, offset, size, uid, gid, mode, crtime and nlink)
+  /// not set by any key and thus defaulted (e.g. size defaults to source size minus offset)
+  pub fn defaulted(&self) -> Vec<&'static str> {
+    ATTRIBUTE_KEYS.iter()
+      .filter(|(_, keys)| !keys.iter().any(|k| self.is_set(k)))
+      .map(|(attr, _)| *attr)
+      .collect()
+  }
+}
+
+/// Config in a line of configs (trimmed), or None for blank and comment lines (starting with #)
+pub fn config_line(line: &str) -> Option<&str> {
+  let line = line.trim();
+  (!line.is_empty() && !line.starts_with('#')).then_some(line)
+}
+
+/// Parse config string of a mapped file (colon-separated options)
+pub fn parse_config(config_str: impl AsRef<str>, duplicate_key: DuplicateKey) -> Result<InodeConfig, ConfigError> {
+  parse_config_detailed(config_str, duplicate_key).map(|parsed| parsed.config)
+}
+
+/// Parse config string of a mapped file and report the keys explicitly set
+pub fn parse_config_detailed(config_str: impl AsRef<str>, duplicate_key: DuplicateKey) -> Result<ParsedConfig, ConfigError> {
+  let assert_opt = |cond: bool, opt_str| -> Result<(), ConfigError> {
+    if !cond {
+      Err(ConfigError::invalid(opt_str))
+    } else {
+      Ok(())
+    }
+  };
+
+  let mut config = InodeConfig::default();
+  let mut end: Option<u64> = None;
+  let mut seed: Option<u64> = None;
+  let mut block_size: Option<u64> = None;
+  let mut offset_blocks: Option<u64> = None;
+  let mut size_blocks: Option<u64> = None;
+  // whether preload is used as a flag (without size)
+  let mut preload_flag: Option<bool> = None;
+  if config_str.as_ref().is_empty() {
+    // use default config
+    return Ok(ParsedConfig { config, keys: Vec::new() });
+  }
+  // keys set in this config
+  let mut keys = Vec::new();
+  let opts = split_options(config_str.as_ref());
+  for opt_str in opts.iter().map(String::as_str) {
+    // split at the first "=" only to allow it in values (e.g. regex)
+    let parts = match opt_str.split_once('=') {
+      Some((key, value)) => [key, value],
+      None if FLAG_KEYS.contains(&opt_str) => [opt_str, ""],
+      None => return Err(ConfigError::MissingValue(opt_str.into()))
+    };
+    if parts[0] == "preload" {
+      // different forms conflict rather than repeat
+      let flag = opt_str == "preload";
+      if preload_flag.is_some_and(|f| f != flag) {
+        return Err(ConfigError::Conflict("preload can't be used with preload=<size>".into()));
+      }
+      preload_flag = Some(flag);
+    }
+    if keys.contains(&parts[0]) && matches!(duplicate_key, DuplicateKey::Error) {
+      return Err(ConfigError::DuplicateKey(parts[0].into()));
+    }
+    keys.push(parts[0]);
+    match parts[0] {
+      "name" => config.name = Some(parts[1].into()),
+      "source" => match parts[1] {
+        // use ./zero or ./random for real files
+        "zero" => config.synthetic = Some(Synthetic::Zero),
+        "random" => config.synthetic = Some(Synthetic::Random(0)),
+        _ => config.sources = parts[1].split(',').map(PathBuf::from).collect()
+      },
+      "sparse" => config.sparse = parts[1].parse().map_err(|_| ConfigError::invalid(opt_str))?,
+      "pad" => config.pad = parts[1].parse().map_err(|_| ConfigError::invalid(opt_str))?,
+      "preload" => config.preload = Some(match parts[1] {
+        "" => u64::MAX,
+        v => parse_size(opt_str, v)?
+      }),
+      "seed" => seed = Some(parse_number(opt_str, parts[1])?),
+      "stripe" => config.stripe = Some(match parts[1] {
+        "rr" => Stripe::RoundRobin,
+        "offset" => Stripe::Offset,
+        _ => return Err(ConfigError::invalid(opt_str))
+      }),
+      "offset" => config.offset = Some(parse_size(opt_str, parts[1])?),
+      "after" => config.after = Some(parts[1].into()),
+      "compute" => config.compute = Some(parts[1].into()),
+      "size" => match parts[1].strip_prefix('-') {
+        // stop before the end of source
+        Some(trailer) => config.trailer = Some(parse_size(opt_str, trailer)?),
+        None => config.size = Some(parse_size(opt_str, parts[1])?)
+      },
+      "end" => end = Some(parse_size(opt_str, parts[1])?),
+      "block_size" => {
+        let size = parse_size(opt_str, parts[1])?;
+        assert_opt(size > 0, opt_str)?;
+        block_size = Some(size);
+      },
+      "offset_blocks" => offset_blocks = Some(parse_number(opt_str, parts[1])?),
+      "size_blocks" => size_blocks = Some(parse_number(opt_str, parts[1])?),
+      "range" => {
+        let (start, range_end) = parts[1].split_once('-').ok_or(ConfigError::invalid(opt_str))?;
+        config.offset = Some(parse_size(opt_str, start)?);
+        end = Some(parse_size(opt_str, range_end)?);
+      },
+      "uid" => config.uid = Some(parse_uid(opt_str, parts[1])?),
+      "gid" => config.gid = Some(parse_gid(opt_str, parts[1])?),
+      "mode" => {
+        let mode = u16::from_str_radix(parts[1], 8)
+          .map_err(|e| ConfigError::BadNumber(opt_str.to_string(), e))?;
+        if mode > 0o7777 {
+          return Err(ConfigError::invalid(opt_str));
+        }
+        config.mode = Some(mode);
+      },
+      "nlink" => {
+        let nlink = parse_number(opt_str, parts[1])?;
+        assert_opt(nlink >= 1, opt_str)?;
+        config.nlink = Some(nlink);
+      },
+      "crtime" => config.crtime = Some(parse_time(opt_str, parts[1])?),
+      "lines" => {
+        let (start, end) = parts[1].split_once('-').ok_or(ConfigError::invalid(opt_str))?;
+        let start: u64 = parse_number(opt_str, start)?;
+        let end: Option<u64> = if end.is_empty() { None } else { Some(parse_number(opt_str, end)?) };
+        assert_opt(start >= 1 && end.is_none_or(|e| e >= start), opt_str)?;
+        config.lines = Some((start, end));
+      },
+      "regex_start" => config.regex_start = Some(Regex::new(parts[1]).map_err(|err| ConfigError::BadValue(err.to_string()))?),
+      "regex_end" => config.regex_end = Some(Regex::new(parts[1]).map_err(|err| ConfigError::BadValue(err.to_string()))?),
+      "raid0" => {
+        let width = parse_number(opt_str, parts[1])?;
+        assert_opt(width > 0, opt_str)?;
+        config.raid0 = Some(width);
+      },
+      "find" => {
+        let sig = transform::parse_hex(parts[1]).filter(|s| !s.is_empty());
+        config.find = Some(sig.ok_or(ConfigError::BadValue(format!("invalid hex signature: {}", parts[1])))?);
+      },
+      "find_from" => config.find_from = parse_size(opt_str, parts[1])?,
+      "max_scan" => config.max_scan = Some(parse_size(opt_str, parts[1])?),
+      "rdev" => {
+        let (major, minor) = parts[1].split_once(',').ok_or(ConfigError::invalid(opt_str))?;
+        config.rdev = Some(encode_dev(parse_number(opt_str, major)?, parse_number(opt_str, minor)?));
+      },
+      "xor" => {
+        let key = transform::parse_hex(parts[1]).filter(|k| !k.is_empty());
+        config.xor = Some(key.ok_or(ConfigError::BadValue(format!("invalid hex key: {}", parts[1])))?);
+      },
+      "pattern" => {
+        let pattern = transform::parse_hex(parts[1]).filter(|p| !p.is_empty());
+        let pattern = pattern.ok_or(ConfigError::BadValue(format!("invalid hex pattern: {}", parts[1])))?;
+        config.synthetic = Some(Synthetic::Pattern(pattern));
+      },
+      "aes_ctr_key_file" => config.aes_ctr_key = Some(KeySource::File(parts[1].into())),
+      "aes_ctr_key_env" => config.aes_ctr_key = Some(KeySource::Env(parts[1].into())),
+      "aes_ctr_iv" => {
+        config.aes_ctr_iv = transform::parse_hex(parts[1])
+          .and_then(|iv| iv.try_into().ok())
+          .ok_or(ConfigError::BadValue(format!("invalid AES-CTR IV (16 bytes in hex): {}", parts[1])))?;
+      },
+      "transform" => {
+        config.transforms = parts[1].split(',')
+          .map(|name| transform::from_name(name).ok_or(ConfigError::BadValue(format!("unknown transform: {}", name))))
+          .collect::<Result<_, _>>()?;
+      },
+      "kind" => config.kind = Some(match parts[1] {
+        "file" => FileType::RegularFile,
+        "char" => FileType::CharDevice,
+        "block" => FileType::BlockDevice,
+        _ => return Err(ConfigError::invalid(opt_str))
+      }),
+      _ => return Err(ConfigError::UnknownKey(opt_str.into()))
+    };
+  }
+  for (a, b) in CONFLICTING_KEYS {
+    if keys.contains(a) && keys.contains(b) {
+      return Err(ConfigError::Conflict(format!("{} can't be used with {}", a, b)));
+    }
+  }
+  for (key, blocks, value) in [("offset_blocks", offset_blocks, &mut config.offset), ("size_blocks", size_blocks, &mut config.size)] {
+    let Some(blocks) = blocks else { continue };
+    let block_size = block_size.ok_or_else(|| ConfigError::BadValue(format!("{} requires block_size", key)))?;
+    *value = Some(blocks.checked_mul(block_size)
+      .ok_or_else(|| ConfigError::BadValue(format!("{} ({}) * block_size ({}) overflows", key, blocks, block_size)))?);
+  }
+  if let (Some(offset), Some(size)) = (config.offset, config.size) {
+    if offset.checked_add(size).is_none() {
+      return Err(ConfigError::BadValue(format!("offset ({}) + size ({}) overflows", offset, size)));
+    }
+  }
+  if let Some(end) = end {
+    let offset = config.offset.unwrap_or(0);
+    if end <= offset {
+      return Err(ConfigError::BadValue(format!("end ({}) must be greater than offset ({})", end, offset)));
+    }
+    config.size = Some(end - offset);
+  }
+  if keys.contains(&"find_from") && config.find.is_none() {
+    return Err(ConfigError::BadValue("find_from requires find".into()));
+  }
+  if let Some(synthetic) = &mut config.synthetic {
+    let synthetic_key = if keys.contains(&"pattern") { "pattern" } else { "source" };
+    if let Some(key) = keys.iter().find(|k| SOURCE_KEYS.contains(k)) {
+      return Err(ConfigError::Conflict(format!("{} can't be used with {}", key, synthetic_key)));
+    }
+    if config.trailer.is_some() {
+      return Err(ConfigError::BadValue(format!("negative size can't be used with {}", synthetic_key)));
+    }
+    if config.size.is_none() {
+      return Err(ConfigError::BadValue(format!("{} requires size or end", synthetic_key)));
+    }
+    match (synthetic, seed) {
+      (Synthetic::Random(s), Some(seed)) => *s = seed,
+      (_, Some(_)) => return Err(ConfigError::BadValue("seed requires source=random".into())),
+      _ => ()
+    };
+  } else if seed.is_some() {
+    return Err(ConfigError::BadValue("seed requires source=random".into()));
+  }
+  if config.aes_ctr_key.is_some() && !cfg!(feature = "crypto") {
+    return Err(ConfigError::BadValue("AES-CTR requires rangefs built with crypto feature".into()));
+  }
+  let keys = keys.into_iter().map(String::from).collect();
+  Ok(ParsedConfig { config, keys })
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn parse(config: &str) -> Result<InodeConfig, ConfigError> {
+    parse_config(config, DuplicateKey::Error)
+  }
+
+  #[test]
+  fn offset_near_max() {
+    let max = u64::MAX.to_string();
+    let config = parse(&format!("offset={}", max)).unwrap();
+    assert_eq!(config.offset, Some(u64::MAX));
+    for config in [format!("offset={}:size=1", max), format!("offset={}:end=1", max), format!("range={}-1", max)] {
+      assert!(parse(&config).is_err(), "{}", config);
+    }
+    let err = parse(&format!("offset={}:size=10", u64::MAX - 5)).err().unwrap();
+    assert_eq!(err.to_string(), format!("offset ({}) + size (10) overflows", u64::MAX - 5));
+  }
+
+  #[test]
+  fn conflicting_keys() {
+    let value = |key: &str| match key {
+      "range" => "0-5",
+      "stripe" => "rr",
+      "lines" => "1-2",
+      "after" | "regex_start" | "regex_end" => "a",
+      "find" | "pattern" => "00",
+      "compute" | "source" | "aes_ctr_key_file" => "/x",
+      "aes_ctr_key_env" => "KEY",
+      _ => "1"
+    };
+    for (a, b) in CONFLICTING_KEYS {
+      // either order
+      for (x, y) in [(a, b), (b, a)] {
+        let config = format!("{}={}:{}={}", x, value(x), y, value(y));
+        match parse(&config) {
+          Err(ConfigError::Conflict(msg)) => assert_eq!(msg, format!("{} can't be used with {}", a, b)),
+          other => panic!("{}: {:?}", config, other.map(|_| ()).map_err(|e| e.to_string()))
+        };
+      }
+    }
+    // preload value and flag (even if the last one wins)
+    for config in ["preload:preload=4K", "preload=4K:preload"] {
+      for duplicate_key in [DuplicateKey::Error, DuplicateKey::LastWins] {
+        match parse_config(config, duplicate_key) {
+          Err(ConfigError::Conflict(msg)) => assert_eq!(msg, "preload can't be used with preload=<size>"),
+          other => panic!("{}: {:?}", config, other.map(|_| ()).map_err(|e| e.to_string()))
+        };
+      }
+    }
+    assert!(matches!(parse("preload=1K:preload=4K"), Err(ConfigError::DuplicateKey(key)) if key == "preload"));
+    let config = parse_config("preload=1K:preload=4K", DuplicateKey::LastWins).unwrap();
+    assert_eq!(config.preload, Some(4096));
+    // no conflict
+    let config = parse("range=10-100").unwrap();
+    assert_eq!((config.offset, config.size), (Some(10), Some(90)));
+  }
+
+  #[test]
+  fn block_units() {
+    let config = parse("block_size=512:offset_blocks=2").unwrap();
+    assert_eq!(config.offset, Some(1024));
+    let config = parse("block_size=4K:offset_blocks=1:size_blocks=3").unwrap();
+    assert_eq!((config.offset, config.size), (Some(4096), Some(12288)));
+    let config = parse("block_size=512:offset_blocks=2:end=2048").unwrap();
+    assert_eq!((config.offset, config.size), (Some(1024), Some(1024)));
+    // mixed with bytes in another dimension
+    let config = parse("block_size=512:offset_blocks=2:size=3").unwrap();
+    assert_eq!((config.offset, config.size), (Some(1024), Some(3)));
+    for config in ["offset_blocks=2", "block_size=0:offset_blocks=2", "block_size=512:offset_blocks=2:offset=3", "block_size=512:size_blocks=2:size=3", "block_size=1T:size_blocks=99999999"] {
+      assert!(parse(config).is_err(), "{}", config);
+    }
+  }
+
+  #[test]
+  fn negative_size() {
+    let config = parse("size=-256").unwrap();
+    assert_eq!((config.size, config.trailer), (None, Some(256)));
+    for config in ["size=-1:pattern=00", "size=-1:end=5", "size=--1", "size=-x"] {
+      assert!(parse(config).is_err(), "{}", config);
+    }
+  }
+
+  #[test]
+  fn comment_lines() {
+    for line in ["", "  ", "# x", "  # x", "\t#a=1"] {
+      assert_eq!(config_line(line), None, "{:?}", line);
+    }
+    assert_eq!(config_line("  a=1 "), Some("a=1"));
+    assert_eq!(config_line("name=a#b"), Some("name=a#b"));
+  }
+
+  #[test]
+  fn non_ascii_time() {
+    for value in ["2024-01-01T00:00:0é+00:00", "2024-01-01T00:00:00é00:00", "2024-01-01T00:00:00.1é", "202é-01-01T00:00:00Z", "1é"] {
+      assert!(parse_time("crtime", value).is_err(), "{}", value);
+    }
+    assert!(parse(&format!("crtime={}", "2024-01-01T00:00:00+0é:00")).is_err());
+    assert_eq!(parse_time("crtime", "1970-01-01T00:00:01Z").unwrap(), UNIX_EPOCH + Duration::from_secs(1));
+  }
+
+  #[test]
+  fn user_and_group_names() {
+    assert_eq!(parse_uid("uid", "root").unwrap(), 0);
+    assert_eq!(parse_gid("gid", "root").unwrap(), 0);
+    assert_eq!(parse_uid("uid", "1000").unwrap(), 1000);
+    assert!(parse_uid("uid", "rangefs-no-such-user").is_err());
+    assert!(parse_gid("gid", "rangefs-no-such-group").is_err());
+    // looked up from multiple threads
+    let threads: Vec<_> = (0..8).map(|_| std::thread::spawn(|| (0..100).all(|_| parse_uid("uid", "root").is_ok_and(|uid| uid == 0)))).collect();
+    assert!(threads.into_iter().all(|t| t.join().unwrap()));
+  }
+}
@@ -0,0 +1,83 @@
+// Copyright (C) 2023-2024  DCsunset
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::{
+  fs, io::{self, BufRead, BufReader, Write}, thread,
+  os::unix::{fs::{FileTypeExt, PermissionsExt}, net::{UnixListener, UnixStream}},
+  path::Path
+};
+use fuser::Notifier;
+use log::{info, warn};
+use crate::{config::{DuplicateKey, config_line, parse_config}, rangefs::Reloader};
+
+/// Bind the control socket (replacing a stale socket file)
+/// accessible only by the owner
+pub fn bind(path: &Path) -> io::Result<UnixListener> {
+  if fs::symlink_metadata(path).is_ok_and(|m| m.file_type().is_socket()) {
+    fs::remove_file(path)?;
+  }
+  // created with owner-only permissions so it's never accessible by others after bind
+  let umask = unsafe { libc::umask(0o177) };
+  let listener = UnixListener::bind(path);
+  unsafe { libc::umask(umask) };
+  let listener = listener?;
+  fs::set_permissions(path, fs::Permissions::from_mode(0o600))?;
+  Ok(listener)
+}
+
+/// Read configs (one per line until EOF) from a client and replace all mappings with them
+/// (invalidating kernel caches of the old ones)
+fn handle(stream: &UnixStream, reloader: &Reloader, notifier: &Notifier, duplicate_key: DuplicateKey) -> io::Result<()> {
+  let mut configs = Vec::new();
+  let mut result = Ok(());
+  for line in BufReader::new(stream).lines() {
+    let line = line?;
+    let Some(line) = config_line(&line) else {
+      continue;
+    };
+    match parse_config(line, duplicate_key) {
+      Ok(config) => configs.push(config),
+      Err(err) => {
+        result = Err(format!("invalid config {:?}: {}", line, err));
+        break;
+      }
+    };
+  }
+  let reply = match result {
+    Ok(()) if configs.is_empty() => "error: no mapping config\n".to_string(),
+    Ok(()) => match reloader.replace(configs, Some(notifier)) {
+      Ok(count) => {
+        info!("Mappings replaced by control socket ({} files)", count);
+        format!("ok {}\n", count)
+      },
+      Err(err) => format!("error: {:#}\n", err)
+    },
+    Err(msg) => format!("error: {}\n", msg)
+  };
+  let mut stream = stream;
+  stream.write_all(reply.as_bytes())
+}
+
+/// Spawn a thread serving the control socket
+pub fn spawn_server(listener: UnixListener, reloader: Reloader, notifier: Notifier, duplicate_key: DuplicateKey) {
+  thread::spawn(move || {
+    for stream in listener.incoming() {
+      let result = stream.and_then(|stream| handle(&stream, &reloader, &notifier, duplicate_key));
+      if let Err(err) = result {
+        warn!("Error serving control socket: {}", err);
+      }
+    }
+  });
+}
@@ -0,0 +1,64 @@
+// Copyright (C) 2023-2024  DCsunset
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::{env, fs, io};
+use aes::cipher::{KeyIvInit, StreamCipher, StreamCipherSeek};
+use crate::{metadata::KeySource, transform::parse_hex};
+
+type Aes128Ctr = ctr::Ctr128BE<aes::Aes128>;
+type Aes192Ctr = ctr::Ctr128BE<aes::Aes192>;
+type Aes256Ctr = ctr::Ctr128BE<aes::Aes256>;
+
+/// AES-CTR cipher with key and initial counter block
+#[derive(Clone)]
+pub struct AesCtr {
+  key: Vec<u8>,
+  iv: [u8; 16]
+}
+
+impl AesCtr {
+  /// Load hex key from its source
+  pub fn load(key_source: &KeySource, iv: [u8; 16]) -> io::Result<Self> {
+    let text = match key_source {
+      KeySource::File(path) => fs::read_to_string(path)?,
+      KeySource::Env(var) => env::var(var)
+        .map_err(|e| io::Error::other(format!("error reading key from env {}: {}", var, e)))?
+    };
+    let key = parse_hex(text.trim())
+      .ok_or_else(|| io::Error::other("invalid hex key"))?;
+    if ![16, 24, 32].contains(&key.len()) {
+      return Err(io::Error::other(format!("invalid AES key length: {} bytes", key.len())));
+    }
+    Ok(Self { key, iv })
+  }
+
+  /// Decrypt data at position of the stream
+  pub fn apply_at(&self, pos: u64, data: &mut [u8]) {
+    match self.key.len() {
+      16 => apply::<Aes128Ctr>(&self.key, &self.iv, pos, data),
+      24 => apply::<Aes192Ctr>(&self.key, &self.iv, pos, data),
+      _ => apply::<Aes256Ctr>(&self.key, &self.iv, pos, data)
+    };
+  }
+}
+
+fn apply<C: KeyIvInit + StreamCipher + StreamCipherSeek>(key: &[u8], iv: &[u8], pos: u64, data: &mut [u8]) {
+  // key length is checked when loading
+  if let Ok(mut cipher) = C::new_from_slices(key, iv) {
+    // CTR is seekable so any window can be decrypted
+    cipher.seek(pos);
+    cipher.apply_keystream(data);
+  }
+}
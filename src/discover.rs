@@ -0,0 +1,153 @@
+// Copyright (C) 2023-2024  DCsunset
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::{fs, collections::HashMap, path::{Path, PathBuf}};
+use anyhow::{Result, anyhow, Context};
+use log::info;
+use crate::{config::{DuplicateKey, parse_config_detailed}, metadata::InodeConfig};
+
+/// Keys not allowed in rules (name and source are from the discovered file)
+const RULE_EXCLUDED_KEYS: [&str; 4] = ["name", "source", "pattern", "after"];
+
+/// Config applied to discovered files whose relative paths match the glob pattern
+#[derive(Clone)]
+pub struct Rule {
+  pub pattern: glob::Pattern,
+  pub config: InodeConfig
+}
+
+/// Parse rule in the form of <pattern>:<config>
+pub fn parse_rule(rule: &str, duplicate_key: DuplicateKey) -> Result<Rule> {
+  let (pattern, config) = rule.split_once(':')
+    .ok_or_else(|| anyhow!("invalid rule {:?} (expected <pattern>:<config>)", rule))?;
+  let pattern = glob::Pattern::new(pattern)
+    .with_context(|| format!("invalid pattern in rule {:?}", rule))?;
+  let parsed = parse_config_detailed(config, duplicate_key)
+    .with_context(|| format!("invalid config in rule {:?}", rule))?;
+  if let Some(key) = RULE_EXCLUDED_KEYS.iter().find(|k| parsed.is_set(k)) {
+    return Err(anyhow!("{} can't be used in rule {:?}", key, rule));
+  }
+  Ok(Rule { pattern, config: parsed.config })
+}
+
+/// Directory whose files are discovered and mapped (rescanned at runtime)
+#[derive(Clone)]
+pub struct DirSource {
+  pub dir: PathBuf,
+  /// Also discover files in subdirectories
+  pub recursive: bool,
+  /// Rules in order of precedence (the first matching one applies)
+  pub rules: Vec<Rule>
+}
+
+impl DirSource {
+  /// Configs of all regular files in the directory
+  pub fn configs(&self) -> Result<Vec<InodeConfig>> {
+    let mut configs = Vec::new();
+    add_dir(&self.dir, "", self.recursive, &mut configs)?;
+    // joined names of files in subdirectories may collide (e.g. a/b_c and a_b/c)
+    let mut paths = HashMap::new();
+    for config in configs.iter() {
+      if let Some(other) = paths.insert(config.name.as_deref(), &config.sources[0]) {
+        return Err(anyhow!("files {:?} and {:?} in directory {:?} have the same name {:?}",
+          other, config.sources[0], self.dir, config.name.as_deref().unwrap_or_default()));
+      }
+    }
+    for config in configs.iter_mut() {
+      config.discovered = true;
+      let path = config.sources[0].strip_prefix(&self.dir).unwrap_or(&config.sources[0]);
+      if let Some(rule) = self.rules.iter().find(|r| r.pattern.matches_path(path)) {
+        *config = InodeConfig {
+          name: config.name.take(),
+          sources: std::mem::take(&mut config.sources),
+          discovered: true,
+          ..rule.config.clone()
+        };
+      }
+    }
+    Ok(configs)
+  }
+}
+
+fn add_dir(dir: &Path, prefix: &str, recursive: bool, configs: &mut Vec<InodeConfig>) -> Result<()> {
+  let mut entries = fs::read_dir(dir)
+    .and_then(|entries| entries.collect::<Result<Vec<_>, _>>())
+    .with_context(|| format!("error reading directory {:?}", dir))?;
+  // stable order of mapped files
+  entries.sort_by_key(|e| e.file_name());
+  for entry in entries {
+    let path = entry.path();
+    let name = format!("{}{}", prefix, entry.file_name().to_string_lossy());
+    // don't follow symlinks to directories to avoid loops
+    if entry.file_type()?.is_dir() {
+      if recursive {
+        add_dir(&path, &format!("{}_", name), recursive, configs)?;
+      }
+    } else if fs::metadata(&path).is_ok_and(|m| m.is_file()) {
+      configs.push(InodeConfig {
+        name: Some(name),
+        sources: vec![path],
+        ..Default::default()
+      });
+    }
+  }
+  Ok(())
+}
+
+/// Configs exposing every regular file matching the glob pattern as a whole
+pub fn glob_configs(pattern: &str) -> Result<Vec<InodeConfig>> {
+  let paths = glob::glob(pattern)
+    .with_context(|| format!("invalid glob pattern {:?}", pattern))?;
+  let mut configs = Vec::new();
+  for path in paths {
+    let path = path.with_context(|| format!("error matching glob pattern {:?}", pattern))?;
+    if !fs::metadata(&path).is_ok_and(|m| m.is_file()) {
+      continue;
+    }
+    configs.push(InodeConfig {
+      name: path.file_name().map(|n| n.to_string_lossy().into()),
+      sources: vec![path],
+      ..Default::default()
+    });
+  }
+  if configs.is_empty() {
+    return Err(anyhow!("no file matches glob pattern {:?}", pattern));
+  }
+  info!("{} files match glob pattern {:?}", configs.len(), pattern);
+  Ok(configs)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn colliding_names() {
+    let dir = std::env::temp_dir().join(format!("rangefs-{}-discover", std::process::id()));
+    for sub in ["a", "a_b"] {
+      fs::create_dir_all(dir.join(sub)).unwrap();
+    }
+    fs::write(dir.join("a/b_c"), b"1").unwrap();
+    let source = DirSource { dir: dir.clone(), recursive: true, rules: Vec::new() };
+    let names: Vec<_> = source.configs().unwrap().into_iter().map(|c| c.name.unwrap()).collect();
+    assert_eq!(names, ["a_b_c"]);
+    fs::write(dir.join("a_b/c"), b"2").unwrap();
+    let err = source.configs().err().unwrap().to_string();
+    assert!(err.contains("same name \"a_b_c\""), "{}", err);
+    // not joined without recursion
+    assert!(DirSource { recursive: false, ..source }.configs().unwrap().is_empty());
+    fs::remove_dir_all(dir).unwrap();
+  }
+}
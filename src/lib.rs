@@ -0,0 +1,78 @@
+// Copyright (C) 2023-2024  DCsunset
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Map ranges in files to individual files by FUSE.
+//!
+//! The filesystem can be embedded in other programs:
+//!
+//! ```no_run
+//! use rangefs::{RangeFs, FsOptions, DuplicateKey, MountOption, parse_config};
+//!
+//! let configs = vec![
+//!   parse_config("name=header:size=512", DuplicateKey::Error)?,
+//!   parse_config("name=body:offset=512", DuplicateKey::Error)?,
+//! ];
+//! let fs = RangeFs::new(Some("disk.img".into()), configs, FsOptions::default())?;
+//! rangefs::mount(fs, "/mnt/disk", &[MountOption::RO])?;
+//! # Ok::<(), Box<dyn std::error::Error>>(())
+//! ```
+
+mod rangefs;
+mod metadata;
+mod scan;
+mod transform;
+mod synthetic;
+mod overlay;
+#[cfg(feature = "crypto")]
+mod crypto;
+// internal modules used by the binary (not part of the library API)
+#[doc(hidden)]
+pub mod config;
+#[doc(hidden)]
+pub mod stats;
+#[doc(hidden)]
+pub mod watch;
+#[doc(hidden)]
+pub mod manifest;
+#[doc(hidden)]
+pub mod discover;
+#[doc(hidden)]
+pub mod mounts;
+#[doc(hidden)]
+pub mod control;
+#[doc(hidden)]
+pub mod checksum;
+
+use std::{io, path::Path};
+
+pub use crate::rangefs::{RangeFs, FsOptions, OrderBy, Reloader};
+pub use config::{ConfigError, DuplicateKey, ParsedConfig, parse_config, parse_config_detailed};
+pub use metadata::InodeConfig;
+pub use discover::{DirSource, Rule};
+pub use checksum::Checksum;
+pub use fuser::MountOption;
+
+/// Optional Cargo features and whether each is compiled in
+pub const FEATURES: &[(&str, bool)] = &[
+  ("crypto", cfg!(feature = "crypto")),
+  ("zstd", cfg!(feature = "zstd")),
+  ("xz", cfg!(feature = "xz")),
+  ("bench", cfg!(feature = "bench"))
+];
+
+/// Mount the filesystem and block until it's unmounted
+pub fn mount(fs: RangeFs, mount_point: impl AsRef<Path>, options: &[MountOption]) -> io::Result<()> {
+  fuser::mount2(fs, mount_point, options)
+}
@@ -15,33 +15,112 @@
 
 extern crate env_logger;
 
-mod rangefs;
-mod metadata;
+#[cfg(feature = "bench")]
+mod bench;
 
-use std::path::PathBuf;
-use anyhow::{Result, anyhow};
+use std::{path::{Path, PathBuf}, time::Duration, fs, io, thread, sync::{Arc, Mutex, mpsc}, os::unix::{fs::DirBuilderExt, io::AsRawFd}};
+use anyhow::{Result, anyhow, Context};
+use log::{info, warn};
 use clap::Parser;
 use fuser::{self, MountOption};
-use rangefs::RangeFs;
-use metadata::InodeConfig;
+use rangefs::{
+  RangeFs, FsOptions, OrderBy, parse_config, manifest, discover::{self, DirSource}, stats, watch, control, checksum::Checksum,
+  config::{DuplicateKey, config_line, parse_size, parse_uid, parse_gid},
+  mounts::{MountConfig, parse_config_file}
+};
 use daemonize::Daemonize;
 
 #[derive(Parser)]
-#[command(version)]
+#[command(version, long_version = long_version(), allow_missing_positional = true)]
 struct Args {
   /// Config string for each mapped file with colon-separated options
   /// Supported options:
   /// - offset=<offset> (default: 0)
-  /// - size=<size> (default: file_size - offset)
+  /// - after=<mapped_filename> (set offset to the end of another mapped file)
+  /// - size=<size> (default: file_size - offset; limited to available data unless padded)
+  ///   (negative size -<N> stops N bytes before the end of file)
+  /// - pad=true|false (keep size beyond the end of source and read zeros there; default: false)
+  /// - end=<end_offset> (exclusive end of range, alternative to size)
+  /// - range=<offset>-<end_offset> (same as offset and end)
+  /// - block_size=<size> (unit of offset_blocks and size_blocks)
+  /// - offset_blocks=<N> / size_blocks=<N> (offset or size in blocks instead of bytes)
   /// - name=<mapped_filename> (default: source_filename)
-  /// - uid=<uid> (default: source_uid)
-  /// - gid=<gid> (default: source_gid)
+  /// - source=<path>[,<path>...] (equivalent copies of source file; default: global source)
+  /// - stripe=rr|offset (spread reads across sources by round-robin or offset)
+  /// - raid0=<stripe_width> (sources are stripes of a single file in order)
+  /// - uid=<uid|user> (default: source_uid)
+  /// - gid=<gid|group> (default: source_gid)
+  /// - mode=<octal> (default: source_mode)
+  /// - nlink=<N> (link count; default: 1)
+  /// - crtime=<epoch_seconds|RFC3339> (creation time; default: source_crtime)
+  /// - kind=file|char|block (file type to present; default: file)
+  /// - rdev=<major>,<minor> (device number for char/block)
+  /// - xor=<hex_key> (XOR the range with a repeating key before transforms)
+  /// - aes_ctr_key_file=<path> (file of hex key to decrypt the range by AES-CTR; needs crypto feature)
+  /// - aes_ctr_key_env=<var> (env var of hex key instead of file)
+  /// - aes_ctr_iv=<hex> (16-byte initial counter block for AES-CTR; default: 0)
+  /// - transform=<name>[,<name>...] (transforms applied in order: hex, base64, zstd, xz)
+  /// - lines=<start>-[<end>] (1-based line range to map instead of offset and size)
+  /// - regex_start=<regex> (range starts at the first match)
+  /// - regex_end=<regex> (range ends at the end of the first match after start)
+  /// - find=<hex_signature> (range starts at the first occurrence of the signature)
+  /// - find_from=<offset> (offset to start finding the signature; default: 0)
+  /// - compute=<command> (run with source paths at each refresh and print "<offset> <size>" as the range)
+  /// - max_scan=<bytes> (max bytes to scan when resolving lines, regex or find)
+  /// - pattern=<hex> (repeat the pattern instead of reading source; needs size)
+  ///   (source=zero or source=random also generates data without reading source)
+  /// - seed=<seed> (seed for source=random; default: 0)
+  /// - sparse=true|false (skip reading holes in source; default: false)
+  /// - preload[=<bytes>] (load the range or its first bytes into memory at mount)
+  ///
+  /// Sizes and offsets accept binary suffixes K, M, G and T (e.g. 4K)
+  /// Use - to read newline-separated configs from stdin
   #[arg(short, long, verbatim_doc_comment)]
   config: Vec<String>,
 
-  /// Timeout for metadata and cache in seconds
-  #[arg(short, long, default_value_t = 1)]
-  timeout: u64,
+  /// CSV manifest of mapped files with columns name,offset,size,uid,gid
+  /// (an optional header row can specify the columns)
+  #[arg(long, value_name = "PATH")]
+  manifest: Option<PathBuf>,
+
+  /// File listing multiple mounts served by this process
+  /// (a [<mount_point>] line, an optional file=<path> line and configs one per line for each mount)
+  #[arg(long, value_name = "PATH", conflicts_with_all = ["config", "manifest", "dir", "glob", "file", "source", "mount_point"])]
+  config_file: Option<PathBuf>,
+
+  /// Expose every regular file in the directory as a whole under its file name
+  /// (rescanned when listed to follow added and removed files)
+  #[arg(long, value_name = "PATH")]
+  dir: Option<PathBuf>,
+
+  /// Also expose files in subdirectories with --dir
+  #[arg(long, requires = "dir")]
+  recursive: bool,
+
+  /// Config applied to files in --dir whose relative paths match the glob pattern
+  /// (e.g. "*.bin:offset=64"; can be repeated and the first matching rule applies)
+  #[arg(long, value_name = "PATTERN:CONFIG", requires = "dir")]
+  rule: Vec<String>,
+
+  /// Expose every regular file matching the glob pattern as a whole (can be repeated)
+  #[arg(long, value_name = "PATTERN")]
+  glob: Vec<String>,
+
+  /// Default uid or user name of all files (overridden by uid in config)
+  #[arg(long, value_parser = parse_uid_arg)]
+  uid: Option<u32>,
+
+  /// Default gid or group name of all files (overridden by gid in config)
+  #[arg(long, value_parser = parse_gid_arg)]
+  gid: Option<u32>,
+
+  /// How to handle an option repeated in a config
+  #[arg(long, value_enum, default_value_t = DuplicateKey::Error)]
+  duplicate_key: DuplicateKey,
+
+  /// Timeout for metadata and cache in seconds with millisecond precision (0 to always refresh)
+  #[arg(short, long, default_value = "1", value_parser = parse_timeout)]
+  timeout: Duration,
 
   /// Redirect stdout to file (only when in background)
   #[arg(long)]
@@ -55,6 +134,145 @@ struct Args {
   #[arg(long)]
   foreground: bool,
 
+  /// Run in background by plain double fork and setsid instead of daemonize
+  #[arg(long, conflicts_with = "foreground")]
+  detach: bool,
+
+  /// Fail to mount if the metadata of any mapped file can't be read
+  #[arg(long)]
+  fail_on_error: bool,
+
+  /// Allow mounting when all mapped files are empty (e.g. the source file is empty)
+  #[arg(long)]
+  allow_empty: bool,
+
+  /// Treat suspicious configs (e.g. empty mapped file) as errors
+  #[arg(long)]
+  strict: bool,
+
+  /// Use mtime and ctime of the source file for the root directory
+  #[arg(long)]
+  root_time_from_source: bool,
+
+  /// Max bytes to scan when resolving lines, regex or find for all mapped files
+  /// (caps max_scan in config)
+  #[arg(long, value_name = "BYTES", value_parser = parse_size_arg)]
+  max_scan_bytes: Option<u64>,
+
+  /// Split reads from source files into reads of at most this size
+  /// (for sources like network mounts that perform poorly with large reads)
+  #[arg(long, value_name = "BYTES", value_parser = parse_nonzero_size_arg)]
+  source_read_chunk: Option<u64>,
+
+  /// Reload preloaded data after timeout if the source changed (captured once by default)
+  #[arg(long)]
+  refresh_preload: bool,
+
+  /// Max time in seconds to preload each mapped file at mount
+  /// (the file is read on demand instead if it takes longer)
+  #[arg(long, value_name = "SECS", value_parser = parse_interval)]
+  preload_timeout: Option<Duration>,
+
+  /// Permission bits to clear from all mapped files in octal (e.g. 022)
+  #[arg(long, value_name = "MASK", value_parser = parse_mode, default_value = "0")]
+  perm_mask: u32,
+
+  /// Keep source files opened at mount and serve reads from them
+  /// even if they are deleted or replaced (instead of following the paths)
+  #[arg(long)]
+  pin_source: bool,
+
+  /// Present source files as they were at mount:
+  /// reads fail with ESTALE if a source is modified (by mtime or size)
+  #[arg(long)]
+  snapshot: bool,
+
+  /// Bypass the page cache so every read hits the source (lower throughput)
+  #[arg(long)]
+  direct_io: bool,
+
+  /// Fail reads extending past the end of a file with EINVAL instead of returning short reads
+  /// (implies --direct-io as reads through the page cache are page-aligned)
+  #[arg(long)]
+  strict_bounds: bool,
+
+  /// Order of files in listings (with offset, mapped files without names are named <source>.<N>
+  /// numbered by offset)
+  #[arg(long, value_enum, value_name = "KEY", default_value_t = OrderBy::Name)]
+  order_by: OrderBy,
+
+  /// Update access time of mapped files on read (in memory only, not written to source files;
+  /// disabled by noatime in -o)
+  #[arg(long)]
+  track_atime: bool,
+
+  /// Allow writes to mapped files, stored in an in-memory overlay without touching the source
+  #[arg(long)]
+  overlay: bool,
+
+  /// Save modified files to the directory on unmount (discarded by default)
+  #[arg(long, value_name = "DIR", requires = "overlay")]
+  overlay_save: Option<PathBuf>,
+
+  /// Let the kernel cache writes to the overlay and flush them in batches
+  /// (skipped with a warning if the kernel doesn't support it)
+  #[arg(long, requires = "overlay")]
+  writeback_cache: bool,
+
+  /// Max bytes the kernel reads ahead for sequential reads (capped by the kernel)
+  #[arg(long, value_name = "BYTES", value_parser = parse_nonzero_size_arg)]
+  max_readahead: Option<u64>,
+
+  /// Max number of pending background requests (e.g. readahead) in kernel for high-concurrency workloads
+  #[arg(long, value_name = "N", value_parser = clap::value_parser!(u16).range(1..))]
+  max_background: Option<u16>,
+
+  /// Number of pending background requests at which the kernel considers the fs congested
+  /// (default: 3/4 of max background)
+  #[arg(long, value_name = "N", value_parser = clap::value_parser!(u16).range(1..))]
+  congestion_threshold: Option<u16>,
+
+  /// Max number of mapped files to catch a runaway manifest or glob (0 for unlimited)
+  #[arg(long, value_name = "N", default_value = "65536")]
+  max_mappings: usize,
+
+  /// Max number of cached results of line/regex scans (0 to disable)
+  #[arg(long, value_name = "N", default_value_t = 1024)]
+  scan_cache_size: usize,
+
+  /// Control socket to replace all mappings at runtime
+  /// (send configs one per line and close the write side; replies "ok <count>" or "error: <msg>")
+  #[arg(long, value_name = "PATH")]
+  control: Option<PathBuf>,
+
+  /// Watch source files every interval in seconds to wake up pollers (e.g. for growing files)
+  #[arg(long, value_name = "SECS", value_parser = parse_interval)]
+  watch: Option<Duration>,
+
+  /// Write metrics in Prometheus text format to this file periodically
+  #[arg(long, value_name = "PATH")]
+  metrics_file: Option<PathBuf>,
+
+  /// Interval in seconds to write metrics file
+  #[arg(long, value_name = "SECS", value_parser = parse_interval, default_value = "10")]
+  metrics_interval: Duration,
+
+  /// Show the status file (.status) in directory listing
+  #[arg(long)]
+  show_status: bool,
+
+  /// Add a read-only .rangefs directory describing mapped files and their sources
+  #[arg(long)]
+  introspect: bool,
+
+  /// Add a checksum file <name>.<algorithm> for each mapped file (e.g. to verify by sha256sum -c)
+  #[arg(long, value_enum, value_name = "ALGORITHM")]
+  checksum_sidecar: Option<Checksum>,
+
+  /// Add a file combining all mapped files in order (default name: combined)
+  #[arg(long, value_name = "NAME", num_args = 0..=1, require_equals = true, default_missing_value = "combined")]
+  combined: Option<String>,
+
   /// comma-separated mount options for compatibility with mount.fuse and fstab
   #[arg(short)]
   options: Option<String>,
@@ -67,20 +285,72 @@ struct Args {
   #[arg(long)]
   allow_root: bool,
 
+  /// Let the kernel check access by the uid, gid and mode of files
+  /// (otherwise any user allowed by allow_other can read all files)
+  #[arg(long)]
+  default_permissions: bool,
+
   /// Unmount automatically when program exists.
   /// (need --allow-root or --allow-other; auto set one if not specified)
   #[arg(short, long)]
   auto_unmount: bool,
 
+  /// Create the mount point (and its parents) if missing
+  #[arg(long)]
+  mkdir: bool,
+
+  /// Mode (in octal) of directories created by --mkdir
+  #[arg(long, value_name = "MODE", value_parser = parse_mode, default_value = "755")]
+  mkdir_mode: u32,
+
+  /// Resolve all mappings and print them (offset, size and sources) without mounting
+  #[arg(long)]
+  check: bool,
+
+  /// Print resolved mappings in JSON with --check
+  #[arg(long, requires = "check")]
+  json: bool,
+
+  /// Remove the mount point on clean unmount if it's an empty directory
+  #[arg(long)]
+  rmdir_on_exit: bool,
+
+  /// Mount even if the mount point is already a mount point (stacking on it)
+  #[arg(long)]
+  force: bool,
+
+  /// Filesystem name shown in mount table (default: source path)
+  #[arg(long)]
+  fsname: Option<String>,
+
+  /// Filesystem subtype shown in mount table (default: rangefs)
+  #[arg(long)]
+  subtype: Option<String>,
+
   /// Overwrite source file (useful for customizing fsname)
   #[arg(short, long)]
   file: Option<PathBuf>,
 
   /// source file to map ranges from
-  source: PathBuf,
+  /// (optional if all mapped files have their own sources or are synthetic)
+  source: Option<PathBuf>,
 
   /// mount point
-  mount_point: PathBuf
+  #[arg(required_unless_present = "config_file")]
+  mount_point: Option<PathBuf>
+}
+
+/// Version with compiled-in features (shown by --version)
+fn long_version() -> &'static str {
+  let list = |enabled: bool| {
+    let names: Vec<_> = rangefs::FEATURES.iter().filter(|(_, e)| *e == enabled).map(|(name, _)| *name).collect();
+    if names.is_empty() { "(none)".to_string() } else { names.join(", ") }
+  };
+  // built once per process
+  Box::leak(format!(
+    "{}\nfeatures: {}\nnot compiled: {}",
+    env!("CARGO_PKG_VERSION"), list(true), list(false)
+  ).into_boxed_str())
 }
 
 pub fn mount_option_from_str(s: &str) -> MountOption {
@@ -108,33 +378,230 @@ pub fn mount_option_from_str(s: &str) -> MountOption {
   }
 }
 
-pub fn parse_config(config_str: impl AsRef<str>) -> Result<InodeConfig> {
-  let assert_opt = |cond: bool, opt_str| -> Result<()> {
-    if !cond {
-      Err(anyhow!("invalid option: {}", opt_str))
-    } else {
-      Ok(())
-    }
+/// Parse size with optional binary suffix
+pub fn parse_size_arg(s: &str) -> Result<u64> {
+  Ok(parse_size("size", s)?)
+}
+
+/// Parse non-zero size with optional binary suffix
+pub fn parse_nonzero_size_arg(s: &str) -> Result<u64> {
+  let size = parse_size_arg(s)?;
+  if size == 0 {
+    return Err(anyhow!("invalid size: {}", s));
+  }
+  Ok(size)
+}
+
+/// Parse uid or user name
+pub fn parse_uid_arg(s: &str) -> Result<u32> {
+  Ok(parse_uid("uid", s)?)
+}
+
+/// Parse gid or group name
+pub fn parse_gid_arg(s: &str) -> Result<u32> {
+  Ok(parse_gid("gid", s)?)
+}
+
+/// Parse file mode in octal
+pub fn parse_mode(s: &str) -> Result<u32> {
+  let mode = u32::from_str_radix(s.strip_prefix("0o").unwrap_or(s), 8)?;
+  if mode > 0o7777 {
+    return Err(anyhow!("invalid mode: {}", s));
+  }
+  Ok(mode)
+}
+
+/// Parse timeout in (fractional) seconds
+pub fn parse_timeout(s: &str) -> Result<Duration> {
+  let secs: f64 = s.parse()?;
+  if !secs.is_finite() || secs < 0. {
+    return Err(anyhow!("invalid timeout: {}", s));
+  }
+  Ok(Duration::from_millis((secs * 1000.).round() as u64))
+}
+
+pub fn parse_interval(s: &str) -> Result<Duration> {
+  let interval = parse_timeout(s)?;
+  if interval.is_zero() {
+    return Err(anyhow!("invalid interval: {}", s));
+  }
+  Ok(interval)
+}
+
+/// Add allow_other if auto_unmount is set without allow_root or allow_other
+/// (returns whether it's added)
+fn ensure_allow_other(options: &mut Vec<MountOption>) -> bool {
+  let allowed = options.iter().any(|o| matches!(o, MountOption::AllowOther | MountOption::AllowRoot));
+  if options.contains(&MountOption::AutoUnmount) && !allowed {
+    options.push(MountOption::AllowOther);
+    return true;
+  }
+  false
+}
+
+/// Whether other users are allowed without kernel permission checks
+fn unchecked_allow_other(options: &[MountOption]) -> bool {
+  options.contains(&MountOption::AllowOther) && !options.contains(&MountOption::DefaultPermissions)
+}
+
+/// Likely cause of a mount error for common failures
+fn mount_error_cause(err: &io::Error) -> Option<&'static str> {
+  // errno first as EPERM is also of kind PermissionDenied
+  match (err.raw_os_error(), err.kind()) {
+    (Some(libc::EBUSY), _) => Some("mount point is busy"),
+    (Some(libc::ENOTCONN), _) => Some("mount point is a disconnected mount; unmount it by fusermount -u"),
+    (Some(libc::EPERM), _) => Some("not permitted; run as root or install fusermount"),
+    (_, io::ErrorKind::PermissionDenied) => Some(
+      "not permitted; allow_other and allow_root require user_allow_other in /etc/fuse.conf"
+    ),
+    (_, io::ErrorKind::NotFound) => Some("fusermount or /dev/fuse not found; install fuse or try 'modprobe fuse'"),
+    _ => None
+  }
+}
+
+/// Fork and exit in the parent
+fn fork_exit_parent() -> io::Result<()> {
+  match unsafe { libc::fork() } {
+    -1 => Err(io::Error::last_os_error()),
+    0 => Ok(()),
+    _ => unsafe { libc::_exit(0) }
+  }
+}
+
+/// Detach from the terminal by double fork and setsid
+/// (stdin and unspecified stdout/stderr are redirected to /dev/null)
+fn detach(stdout: Option<&Path>, stderr: Option<&Path>) -> Result<()> {
+  // open files before forking so errors are reported to the caller
+  let null = fs::OpenOptions::new().read(true).write(true).open("/dev/null")?;
+  let out = match stdout {
+    Some(path) => fs::File::create(path)?,
+    None => null.try_clone()?
   };
+  let err = match stderr {
+    Some(path) => fs::File::create(path)?,
+    None => null.try_clone()?
+  };
+  fork_exit_parent()?;
+  if unsafe { libc::setsid() } < 0 {
+    return Err(io::Error::last_os_error()).context("error creating session");
+  }
+  // not a session leader so it can't acquire a terminal again
+  fork_exit_parent()?;
+  for (file, fd) in [(&null, 0), (&out, 1), (&err, 2)] {
+    if unsafe { libc::dup2(file.as_raw_fd(), fd) } < 0 {
+      return Err(io::Error::last_os_error()).context("error redirecting stdio");
+    }
+  }
+  Ok(())
+}
+
+/// Filesystem type of the mount at the path if it's a mount point (by /proc/mounts)
+fn existing_mount(path: &Path) -> Option<String> {
+  let path = path.canonicalize().ok()?;
+  let mounts = fs::read_to_string("/proc/mounts").ok()?;
+  // the last one is on top if stacked
+  mounts.lines().rev().find_map(|line| {
+    let mut fields = line.split(' ');
+    let (_, target, fstype) = (fields.next()?, fields.next()?, fields.next()?);
+    (unescape_mount_field(target) == path.as_os_str().to_string_lossy()).then(|| fstype.to_string())
+  })
+}
 
-  let mut config = InodeConfig::default();
-  if config_str.as_ref().is_empty() {
-    // use default config
-    return Ok(config);
-  }
-  for opt_str in config_str.as_ref().split(":") {
-    let parts: Vec<_> = opt_str.split("=").collect();
-    assert_opt(parts.len() == 2, opt_str)?;
-    match parts[0] {
-      "name" => config.name = Some(parts[1].into()),
-      "offset" => config.offset = Some(parts[1].parse()?),
-      "size" => config.size = Some(parts[1].parse()?),
-      "uid" => config.uid = Some(parts[1].parse()?),
-      "gid" => config.gid = Some(parts[1].parse()?),
-      _ => assert_opt(false, opt_str)?
+/// Unescape octal sequences (e.g. `\040` for space) in a field of /proc/mounts
+fn unescape_mount_field(field: &str) -> String {
+  let bytes = field.as_bytes();
+  let mut out = Vec::with_capacity(bytes.len());
+  let mut i = 0;
+  while i < bytes.len() {
+    let escaped = bytes.get(i + 1..i + 4)
+      .and_then(|d| std::str::from_utf8(d).ok())
+      .and_then(|d| u8::from_str_radix(d, 8).ok());
+    match (bytes[i], escaped) {
+      (b'\\', Some(b)) => {
+        out.push(b);
+        i += 4;
+      },
+      (b, _) => {
+        out.push(b);
+        i += 1;
+      }
     };
   }
-  Ok(config)
+  String::from_utf8_lossy(&out).into()
+}
+
+type Unmounters = Arc<Mutex<Vec<fuser::SessionUnmounter>>>;
+
+/// Unmount all sessions (no-op for unmounted ones)
+fn unmount_all(unmounters: &Unmounters) {
+  for unmounter in unmounters.lock().unwrap().iter_mut() {
+    let _ = unmounter.unmount();
+  }
+}
+
+/// Unmount all sessions on SIGINT, SIGTERM or SIGHUP
+/// (signals are blocked in this and later spawned threads to be handled by a dedicated thread)
+fn spawn_signal_handler(unmounters: Unmounters) -> io::Result<()> {
+  let mut set: libc::sigset_t = unsafe { std::mem::zeroed() };
+  unsafe {
+    libc::sigemptyset(&mut set);
+    for sig in [libc::SIGINT, libc::SIGTERM, libc::SIGHUP] {
+      libc::sigaddset(&mut set, sig);
+    }
+  }
+  let ret = unsafe { libc::pthread_sigmask(libc::SIG_BLOCK, &set, std::ptr::null_mut()) };
+  if ret != 0 {
+    return Err(io::Error::from_raw_os_error(ret));
+  }
+  thread::spawn(move || {
+    let mut signaled = false;
+    loop {
+      let mut sig = 0;
+      if unsafe { libc::sigwait(&set, &mut sig) } != 0 {
+        return;
+      }
+      // exit if still running (e.g. unmount failed as busy)
+      if signaled {
+        warn!("Exiting on signal {} without unmounting", sig);
+        std::process::exit(1);
+      }
+      info!("Unmounting on signal {}", sig);
+      unmount_all(&unmounters);
+      signaled = true;
+    }
+  });
+  Ok(())
+}
+
+/// Run sessions in threads until all are unmounted
+/// (the others are unmounted if any fails)
+fn run_sessions(sessions: Vec<(PathBuf, bool, fuser::Session<RangeFs>)>, unmounters: Unmounters, rmdir_on_exit: bool) -> Result<()> {
+  let (sender, receiver) = mpsc::channel();
+  for (mount_point, file_mount, mut session) in sessions {
+    let sender = sender.clone();
+    thread::spawn(move || {
+      let result = session.run();
+      if rmdir_on_exit && !file_mount {
+        // only removed if empty
+        if let Err(err) = fs::remove_dir(&mount_point) {
+          warn!("Error removing mount point {:?}: {}", mount_point, err);
+        }
+      }
+      let _ = sender.send((mount_point, result));
+    });
+  }
+  // ends when all threads exit
+  drop(sender);
+  let mut error = None;
+  while let Ok((mount_point, result)) = receiver.recv() {
+    if let Err(err) = result {
+      if error.is_none() {
+        unmount_all(&unmounters);
+      }
+      error.get_or_insert(anyhow::Error::from(err).context(format!("error serving {:?}", mount_point)));
+    }
+  }
+  error.map_or(Ok(()), Err)
 }
 
 fn main() -> Result<()> {
@@ -143,11 +610,16 @@ fn main() -> Result<()> {
     .write_style("RANGEFS_LOG_STYLE");
   env_logger::init_from_env(env);
 
+  // hidden subcommand not conflicting with positional args
+  #[cfg(feature = "bench")]
+  if std::env::args_os().nth(1).is_some_and(|a| a == "bench") {
+    return bench::run(<bench::BenchArgs as Parser>::parse_from(std::env::args_os().skip(1)));
+  }
+
   let args = Args::parse();
   let mut options = vec![
-    MountOption::RO,
-    MountOption::FSName(args.source.to_string_lossy().into()),
-    MountOption::Subtype("rangefs".to_string()),
+    if args.overlay { MountOption::RW } else { MountOption::RO },
+    MountOption::Subtype(args.subtype.clone().unwrap_or_else(|| "rangefs".to_string())),
   ];
   if args.allow_other {
     options.push(MountOption::AllowOther);
@@ -158,35 +630,57 @@ fn main() -> Result<()> {
   if args.auto_unmount {
     options.push(MountOption::AutoUnmount);
   }
+  if args.default_permissions {
+    options.push(MountOption::DefaultPermissions);
+  }
 
-  let mut file = args.file;
+  let mut file = args.file.or(args.source);
   let mut timeout = args.timeout;
-  let mut configs = args.config.iter().map(parse_config).collect::<Result<Vec<_>, _>>()?;
+  let mut configs = Vec::new();
+  for c in args.config.iter() {
+    if c == "-" {
+      // read before daemonizing as stdin is closed after that
+      for line in io::stdin().lines() {
+        let line = line.context("error reading configs from stdin")?;
+        if let Some(line) = config_line(&line) {
+          configs.push(parse_config(line, args.duplicate_key)?);
+        }
+      }
+    } else {
+      configs.push(parse_config(c, args.duplicate_key)?);
+    }
+  }
   let mut stdout = args.stdout;
   let mut stderr = args.stderr;
+  let mut fail_on_error = args.fail_on_error;
+  let mut allow_empty = args.allow_empty;
+  let mut track_atime = args.track_atime;
 
   if let Some(opt) = args.options {
     for o in opt.split(',').map(mount_option_from_str) {
       match o {
         MountOption::RW => (),
+        MountOption::CUSTOM(x) if x == "fail_on_error" => fail_on_error = true,
+        MountOption::CUSTOM(x) if x == "allow_empty" => allow_empty = true,
+        MountOption::CUSTOM(x) if x == "track_atime" => track_atime = true,
         MountOption::CUSTOM(x) => {
           match x {
             x if x.starts_with("config::") => {
-              for c in x.split("::").skip(1).map(parse_config) {
+              for c in x.split("::").skip(1).map(|c| parse_config(c, args.duplicate_key)) {
                 configs.push(c?);
               }
             },
             x if x.starts_with("file::") => {
-              file = Some(x.split("::").skip(1).next().ok_or(anyhow!("invalid option: {}", x))?.into());
+              file = Some(x.split("::").nth(1).ok_or(anyhow!("invalid option: {}", x))?.into());
             },
             x if x.starts_with("timeout::") => {
-              timeout = x.split("::").skip(1).next().ok_or(anyhow!("invalid option: {}", x))?.parse()?;
+              timeout = parse_timeout(x.split("::").nth(1).ok_or(anyhow!("invalid option: {}", x))?)?;
             },
             x if x.starts_with("stdout::") => {
-              stdout = Some(x.split("::").skip(1).next().ok_or(anyhow!("invalid option: {}", x))?.into());
+              stdout = Some(x.split("::").nth(1).ok_or(anyhow!("invalid option: {}", x))?.into());
             },
             x if x.starts_with("stderr::") => {
-              stderr = Some(x.split("::").skip(1).next().ok_or(anyhow!("invalid option: {}", x))?.into());
+              stderr = Some(x.split("::").nth(1).ok_or(anyhow!("invalid option: {}", x))?.into());
             },
             _ => options.push(MountOption::CUSTOM(x))
           };
@@ -198,28 +692,247 @@ fn main() -> Result<()> {
     }
   }
 
-  if configs.is_empty() {
-    return Err(anyhow!("no mapping config specified"));
+  if track_atime && options.contains(&MountOption::NoAtime) {
+    info!("Not tracking access time as noatime is set");
+    track_atime = false;
   }
 
-  if !args.mount_point.as_path().is_dir() {
-    return Err(anyhow!("mount point doesn't exist or isn't a directory"));
+  if ensure_allow_other(&mut options) {
+    warn!("Adding allow_other for auto_unmount (requires user_allow_other in /etc/fuse.conf if not root)");
   }
+  if unchecked_allow_other(&options) {
+    warn!(
+      "allow_other is set without default_permissions: all users can read all mapped files regardless of their uid, gid and mode; \
+      add --default-permissions (or default_permissions in -o) to enforce them"
+    );
+  }
+
+  if let Some(path) = &args.manifest {
+    configs.extend(manifest::parse_manifest(path)?);
+  }
+  let dir_source = match &args.dir {
+    Some(dir) => Some(DirSource {
+      dir: dir.clone(),
+      recursive: args.recursive,
+      rules: args.rule.iter().map(|r| discover::parse_rule(r, args.duplicate_key)).collect::<Result<_>>()?
+    }),
+    None => None
+  };
+  for pattern in &args.glob {
+    configs.extend(discover::glob_configs(pattern)?);
+  }
+
+  let mounts = match (&args.config_file, args.mount_point) {
+    (Some(path), _) => {
+      if !configs.is_empty() || file.is_some() {
+        return Err(anyhow!("configs and source file must be specified in the config file with --config-file"));
+      }
+      parse_config_file(path, args.duplicate_key)?
+    },
+    (None, Some(mount_point)) => {
+      // files in the directory may be added later
+      if configs.is_empty() && dir_source.is_none() {
+        return Err(anyhow!("no mapping config specified"));
+      }
+      vec![MountConfig { mount_point, file, configs }]
+    },
+    (None, None) => unreachable!("mount point is required without config file")
+  };
+  let single = mounts.len() == 1;
+  if !single && (args.control.is_some() || args.metrics_file.is_some() || args.overlay_save.is_some()) {
+    return Err(anyhow!("--control, --metrics-file and --overlay-save require a single mount"));
+  }
+
+  for MountConfig { mount_point, configs, .. } in mounts.iter() {
+    if args.mkdir && !args.check && !mount_point.exists() {
+      fs::DirBuilder::new()
+        .recursive(true)
+        .mode(args.mkdir_mode)
+        .create(mount_point)
+        .with_context(|| format!("error creating mount point {:?}", mount_point))?;
+    }
 
+    // serve the only mapped file directly if mount point is a file
+    let file_mount = mount_point.is_file();
+    if file_mount && configs.len() != 1 {
+      return Err(anyhow!("mounting on file {:?} requires exactly one config (got {})", mount_point, configs.len()));
+    }
+    if !file_mount && !args.check && !mount_point.is_dir() {
+      return Err(anyhow!("mount point {:?} doesn't exist or isn't a directory or file", mount_point));
+    }
+
+    if !args.force {
+      if let Some(fstype) = existing_mount(mount_point) {
+        return Err(anyhow!("{:?} is already a mount point (type: {}); use --force to mount over it", mount_point, fstype));
+      }
+    }
+  }
+
+  // sources under any mount point become inaccessible after mounting
+  let mount_points: Vec<_> = mounts.iter()
+    .filter_map(|m| Some((m.mount_point.canonicalize().ok()?, &m.mount_point)))
+    .collect();
+  for mount in mounts.iter() {
+    let sources = mount.file.iter()
+      .chain(mount.configs.iter().flat_map(|c| c.sources.iter()))
+      .chain(args.dir.iter());
+    for src in sources {
+      let Ok(src_path) = src.canonicalize() else {
+        continue;
+      };
+      if let Some((_, mount_point)) = mount_points.iter().find(|(p, _)| src_path.starts_with(p)) {
+        return Err(anyhow!("source file {:?} is under mount point {:?}", src, mount_point));
+      }
+    }
+  }
+
+  // init fs before daemonizing so errors are reported to the caller
+  let mut mounted = Vec::new();
+  let mut checks = Vec::new();
+  let mut errored = Vec::new();
+  for MountConfig { mount_point, file, configs } in mounts {
+    // report which mount fails if there are multiple
+    let err_context = |err: anyhow::Error| match single {
+      true => err,
+      false => err.context(format!("error initializing mount {:?}", mount_point))
+    };
+    let file_mount = mount_point.is_file();
+    let mut options = options.clone();
+    options.insert(1, MountOption::FSName(args.fsname.clone().unwrap_or_else(|| {
+      file.as_ref().map_or_else(|| "rangefs".into(), |s| s.to_string_lossy().into())
+    })));
+    let mut fs = RangeFs::new(
+      file,
+      configs,
+      FsOptions {
+        timeout,
+        strict: args.strict,
+        combined: args.combined.clone(),
+        show_status: args.show_status,
+        root_time_from_source: args.root_time_from_source,
+        scan_cache_size: args.scan_cache_size,
+        max_mappings: (args.max_mappings > 0).then_some(args.max_mappings),
+        uid: args.uid,
+        gid: args.gid,
+        file_mount,
+        introspect: args.introspect,
+        max_scan: args.max_scan_bytes,
+        pin_source: args.pin_source,
+        snapshot: args.snapshot,
+        perm_mask: args.perm_mask as u16,
+        refresh_preload: args.refresh_preload,
+        preload_timeout: args.preload_timeout,
+        source_read_chunk: args.source_read_chunk,
+        direct_io: args.direct_io,
+        strict_bounds: args.strict_bounds,
+        order_by: args.order_by,
+        track_atime,
+        dir_source: dir_source.clone(),
+        checksum_sidecar: args.checksum_sidecar,
+        max_readahead: args.max_readahead.map(|v| v.try_into().unwrap_or(u32::MAX)),
+        writeback_cache: args.writeback_cache,
+        max_background: args.max_background,
+        congestion_threshold: args.congestion_threshold,
+        overlay: args.overlay,
+        overlay_save: args.overlay_save.clone(),
+        mount_point: mount_point.clone(),
+        mount_options: options.clone()
+      }
+    ).map_err(err_context)?;
+    if args.check {
+      let check = fs.render_check(args.json);
+      checks.push(match (single, args.json) {
+        (true, _) => check,
+        (false, true) => format!(
+          "{{\"mount_point\":{},\"files\":{}}}",
+          stats::json_string(&mount_point.to_string_lossy()),
+          check.trim_end()
+        ),
+        (false, false) => format!("[{}]\n{}", mount_point.display(), check)
+      });
+      errored.extend(fs.errored_files().into_iter().map(|name| name.to_os_string()));
+      continue;
+    }
+    if fail_on_error {
+      let errored = fs.errored_files();
+      if !errored.is_empty() {
+        return Err(err_context(anyhow!("error reading source file metadata for {:?}", errored)));
+      }
+    }
+    // errored files are handled by fail_on_error
+    if !allow_empty && fs.all_empty() {
+      return Err(err_context(anyhow!(
+        "all mapped files are empty (is the source file empty?); use --allow-empty to mount anyway"
+      )));
+    }
+    mounted.push((mount_point, file_mount, options, fs));
+  }
+  if args.check {
+    match single || !args.json {
+      true => print!("{}", checks.join("\n")),
+      // one object per mount in a JSON array
+      false => println!("[{}]", checks.join(","))
+    };
+    if !errored.is_empty() {
+      return Err(anyhow!("error reading source file metadata for {:?}", errored));
+    }
+    return Ok(());
+  }
+
+  // bind before daemonizing to report errors
+  let control_listener = match &args.control {
+    Some(path) => Some(control::bind(path).with_context(|| format!("error binding control socket {:?}", path))?),
+    None => None
+  };
   let mount_fs = || {
-    fuser::mount2(
-      RangeFs::new(
-        file.unwrap_or(args.source),
-        configs,
-        timeout
-      ),
-      &args.mount_point,
-      &options
-    )
+    let mut sessions = Vec::new();
+    let mut helpers = Vec::new();
+    for (mount_point, file_mount, options, fs) in mounted {
+      let stats = fs.file_stats();
+      let watch_targets = fs.watch_targets();
+      let pollers = fs.pollers();
+      let reloader = fs.reloader();
+      let session = fuser::Session::new(fs, &mount_point, &options)
+        .map_err(|err| {
+          let msg = match mount_error_cause(&err) {
+            Some(cause) => format!("error mounting at {:?} ({})", mount_point, cause),
+            None => format!("error mounting at {:?}", mount_point)
+          };
+          anyhow::Error::from(err).context(msg)
+        })?;
+      helpers.push((session.notifier(), session.notifier(), stats, watch_targets, pollers, reloader));
+      sessions.push((mount_point, file_mount, session));
+    }
+    // block signals before spawning threads so only the handler receives them
+    let unmounters = Arc::new(Mutex::new(
+      sessions.iter_mut().map(|(_, _, session)| session.unmount_callable()).collect()
+    ));
+    spawn_signal_handler(unmounters.clone())?;
+    // spawn after daemonizing as threads don't survive fork
+    let mut control_listener = control_listener;
+    for (notifier, control_notifier, stats, watch_targets, pollers, reloader) in helpers {
+      if let Some(path) = &args.metrics_file {
+        stats::spawn_metrics_writer(path.clone(), args.metrics_interval, stats);
+      }
+      if let Some(interval) = args.watch {
+        watch::spawn_watcher(notifier, interval, watch_targets, pollers);
+      }
+      if let Some(listener) = control_listener.take() {
+        control::spawn_server(listener, reloader, control_notifier, args.duplicate_key);
+      }
+    }
+    let result = run_sessions(sessions, unmounters, args.rmdir_on_exit);
+    if let Some(path) = &args.control {
+      let _ = fs::remove_file(path);
+    }
+    result
   };
 
   if args.foreground {
     mount_fs()?;
+  } else if args.detach {
+    detach(stdout.as_deref(), stderr.as_deref())?;
+    mount_fs()?;
   } else {
     let mut daemon = Daemonize::new().working_directory(".");
     if let Some(stdout) = stdout {
@@ -237,3 +950,44 @@ fn main() -> Result<()> {
 
   Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn mount_error_causes() {
+    let cause = |errno| mount_error_cause(&io::Error::from_raw_os_error(errno));
+    assert_eq!(cause(libc::EPERM), Some("not permitted; run as root or install fusermount"));
+    assert!(cause(libc::EACCES).is_some_and(|c| c.contains("user_allow_other")));
+    assert!(cause(libc::ENOENT).is_some_and(|c| c.contains("not found")));
+    assert_eq!(cause(libc::EBUSY), Some("mount point is busy"));
+    assert_eq!(cause(libc::EIO), None);
+  }
+
+  #[test]
+  fn auto_unmount_adds_allow_other() {
+    use MountOption::*;
+    let mut options = vec![RO, AutoUnmount];
+    assert!(ensure_allow_other(&mut options));
+    assert_eq!(options, [RO, AutoUnmount, AllowOther]);
+    // only once
+    assert!(!ensure_allow_other(&mut options));
+    assert_eq!(options.len(), 3);
+    for mut options in [vec![AutoUnmount, AllowRoot], vec![AllowOther, AutoUnmount], vec![RO]] {
+      let original = options.clone();
+      assert!(!ensure_allow_other(&mut options));
+      assert_eq!(options, original);
+    }
+  }
+
+  #[test]
+  fn perm_mask_in_octal() {
+    assert_eq!(parse_mode("022").unwrap(), 0o022);
+    assert_eq!(parse_mode("0o022").unwrap(), 0o022);
+    assert_eq!(parse_mode("7777").unwrap(), 0o7777);
+    for mask in ["8", "17777", "-1", ""] {
+      assert!(parse_mode(mask).is_err(), "{}", mask);
+    }
+  }
+}
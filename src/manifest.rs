@@ -0,0 +1,120 @@
+// Copyright (C) 2023-2024  DCsunset
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::{fs, path::Path};
+use anyhow::{Result, anyhow, Context};
+use crate::{config::{parse_size, parse_uid, parse_gid}, metadata::InodeConfig};
+
+/// Default columns of manifest without header
+const COLUMNS: [&str; 5] = ["name", "offset", "size", "uid", "gid"];
+
+/// Split a CSV line into fields (double quotes for quoting and "" for escaping)
+fn split_csv(line: &str) -> Result<Vec<String>> {
+  let mut fields = Vec::new();
+  let mut field = String::new();
+  let mut quoted = false;
+  let mut chars = line.chars().peekable();
+  while let Some(c) = chars.next() {
+    match (c, quoted) {
+      ('"', true) if chars.peek() == Some(&'"') => {
+        chars.next();
+        field.push('"');
+      },
+      ('"', true) => quoted = false,
+      // whitespace before an opening quote is ignored
+      ('"', false) if field.trim().is_empty() => {
+        field.clear();
+        quoted = true;
+      },
+      (',', false) => fields.push(std::mem::take(&mut field)),
+      (c, _) => field.push(c)
+    };
+  }
+  if quoted {
+    return Err(anyhow!("unterminated quote"));
+  }
+  fields.push(field);
+  Ok(fields)
+}
+
+/// Parse a row of manifest into config
+fn parse_row(columns: &[String], fields: &[String]) -> Result<InodeConfig> {
+  // missing trailing fields use defaults
+  if fields.len() > columns.len() {
+    return Err(anyhow!("expected at most {} fields but got {}", columns.len(), fields.len()));
+  }
+  let mut config = InodeConfig::default();
+  for (col, value) in columns.iter().zip(fields) {
+    let value = value.trim();
+    // empty fields use defaults
+    if value.is_empty() {
+      continue;
+    }
+    match col.as_str() {
+      "name" => config.name = Some(value.into()),
+      "offset" => config.offset = Some(parse_size(col, value)?),
+      "size" => config.size = Some(parse_size(col, value)?),
+      "uid" => config.uid = Some(parse_uid(col, value)?),
+      "gid" => config.gid = Some(parse_gid(col, value)?),
+      _ => unreachable!("columns are validated")
+    };
+  }
+  if let (Some(offset), Some(size)) = (config.offset, config.size) {
+    if offset.checked_add(size).is_none() {
+      return Err(anyhow!("offset ({}) + size ({}) overflows", offset, size));
+    }
+  }
+  Ok(config)
+}
+
+/// Parse manifest in CSV (columns: name,offset,size,uid,gid)
+/// with an optional header row to specify the columns
+pub fn parse_manifest(path: impl AsRef<Path>) -> Result<Vec<InodeConfig>> {
+  let path = path.as_ref();
+  let content = fs::read_to_string(path)
+    .with_context(|| format!("error reading manifest {:?}", path))?;
+  let mut columns: Vec<String> = COLUMNS.iter().map(|c| c.to_string()).collect();
+  let mut configs = Vec::new();
+  for (i, line) in content.lines().enumerate() {
+    let err_context = || format!("invalid manifest {:?} at line {}", path, i + 1);
+    let line = line.trim();
+    if line.is_empty() || line.starts_with('#') {
+      continue;
+    }
+    let fields = split_csv(line).with_context(err_context)?;
+    // header is the first row with only known column names
+    if configs.is_empty() && fields.iter().all(|f| COLUMNS.contains(&f.trim())) {
+      columns = fields.iter().map(|f| f.trim().to_string()).collect();
+      continue;
+    }
+    configs.push(parse_row(&columns, &fields).with_context(err_context)?);
+  }
+  Ok(configs)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn comments_and_indentation() {
+    let path = std::env::temp_dir().join(format!("rangefs-{}-manifest.csv", std::process::id()));
+    fs::write(&path, "# files\n  name,offset,size  \n\t# indented comment\n\n  \"#a\", 4 ,8\n\"b#c\",1,2\n").unwrap();
+    let configs = parse_manifest(&path).unwrap();
+    fs::remove_file(&path).unwrap();
+    let rows: Vec<_> = configs.iter().map(|c| (c.name.clone().unwrap(), c.offset, c.size)).collect();
+    assert_eq!(rows, [("#a".into(), Some(4), Some(8)), ("b#c".into(), Some(1), Some(2))]);
+  }
+}
@@ -13,19 +13,258 @@
 // You should have received a copy of the GNU Affero General Public License
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
-use std::{time::{SystemTime, Duration}, fs, os::unix::prelude::MetadataExt, path::Path};
+use std::{
+  collections::BTreeMap, ffi::OsString,
+  time::{SystemTime, Duration}, fs, os::unix::{prelude::{MetadataExt, FileExt, AsRawFd}, process::CommandExt},
+  path::{Path, PathBuf}, cmp, io::{self, Read}, process::{Command, Stdio}, sync::{atomic::{AtomicUsize, Ordering}, mpsc}, thread,
+  time::Instant, sync::{Arc, Mutex, MutexGuard, PoisonError}
+};
 
 use fuser::{FileAttr, FileType};
-use libc::{S_IXUSR, S_IXGRP, S_IXOTH, S_IFMT};
-use log::{warn, debug};
+use libc::{ESTALE, S_IXUSR, S_IXGRP, S_IXOTH, S_IFMT};
+use log::{warn, debug, info};
+use regex::bytes::Regex;
+use crate::{checksum::Checksum, scan::{self, ScanCache, ScanKey, ScanParams}, stats::InodeStats, synthetic::Synthetic, transform::{self, SharedTransform}};
+#[cfg(feature = "crypto")]
+use crate::crypto::AesCtr;
+
+/// Max time for a compute command to print the range (killed after it)
+const COMPUTE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Size of each block when striping reads by offset
+const STRIPE_BLOCK_SIZE: u64 = 128 * 1024;
+
+/// How to spread reads across multiple sources
+#[derive(Clone, Copy)]
+pub enum Stripe {
+  /// Pick the next source for each read
+  RoundRobin,
+  /// Pick source by the block index of the read offset
+  Offset
+}
+
+/// Where to read the key from
+#[derive(Clone)]
+pub enum KeySource {
+  File(PathBuf),
+  Env(String)
+}
 
 /// Config for each mapped file
+#[derive(Clone, Default)]
 pub struct InodeConfig {
   pub name: Option<String>,
+  /// Equivalent copies of source file (default: global source file)
+  pub sources: Vec<PathBuf>,
+  /// Spread reads across sources (default: read from the first available one)
+  pub stripe: Option<Stripe>,
+  /// Stripe width if sources are stripes of a single file (RAID0)
+  pub raid0: Option<u64>,
   pub offset: Option<u64>,
   pub size: Option<u64>,
+  /// Bytes to exclude before the end of source (negative size)
+  pub trailer: Option<u64>,
   pub uid: Option<u32>,
   pub gid: Option<u32>,
+  /// Permission bits (default: from source)
+  pub mode: Option<u16>,
+  /// Creation time (default: from source)
+  pub crtime: Option<SystemTime>,
+  /// Link count (default: 1)
+  pub nlink: Option<u32>,
+  /// Permission bits to clear (after mode)
+  pub perm_mask: u16,
+  /// Line range (1-based, inclusive) to map instead of offset and size
+  pub lines: Option<(u64, Option<u64>)>,
+  /// Regex matching the start of the range
+  pub regex_start: Option<Regex>,
+  /// Regex matching the end of the range
+  pub regex_end: Option<Regex>,
+  /// Max bytes to scan when resolving the range
+  pub max_scan: Option<u64>,
+  /// Signature to find in source as the offset
+  pub find: Option<Vec<u8>>,
+  /// Offset to start finding the signature
+  pub find_from: u64,
+  /// Command printing offset and size of the range (run with source paths as arguments)
+  pub compute: Option<PathBuf>,
+  /// Name of the mapped file this range starts after
+  pub after: Option<String>,
+  /// File type to present (default: regular file)
+  pub kind: Option<FileType>,
+  /// Device number (encoded) for char/block device
+  pub rdev: Option<u32>,
+  /// Repeating key to XOR the range with (applied before transforms)
+  pub xor: Option<Vec<u8>>,
+  /// Detect holes in source to avoid reading them
+  pub sparse: bool,
+  /// Keep the configured size beyond the end of source and read zeros there
+  pub pad: bool,
+  /// Keep source files opened at mount instead of following their paths
+  pub pin: bool,
+  /// Keep metadata captured at mount and fail reads if the source changes
+  pub snapshot: bool,
+  /// Discovered in the source directory (removed when the file is gone)
+  pub discovered: bool,
+  /// Max size of each read from source files
+  pub read_chunk: Option<u64>,
+  /// Max bytes to preload into memory at mount (u64::MAX for the whole range)
+  pub preload: Option<u64>,
+  /// Reload preloaded data when outdated and the source changed
+  pub refresh_preload: bool,
+  /// Data generated instead of reading source
+  pub synthetic: Option<Synthetic>,
+  /// Source of hex key to decrypt the range with AES-CTR (applied before transforms)
+  pub aes_ctr_key: Option<KeySource>,
+  /// Initial counter block for AES-CTR
+  pub aes_ctr_iv: [u8; 16],
+  /// Transforms applied to the range in order
+  pub transforms: Vec<SharedTransform>,
+}
+
+/// Content of a virtual file
+#[derive(Clone)]
+pub enum Content {
+  /// Range in source files
+  Range,
+  /// Generated data not backed by source
+  Synthetic,
+  /// Concatenation of other mapped files (by inode)
+  Combined(Vec<u64>),
+  /// Status of the filesystem in JSON
+  Status,
+  /// Subdirectory with inode of its parent and entries of name and inode
+  Dir(u64, BTreeMap<OsString, u64>),
+  /// Description of a mapped file (by inode)
+  Describe(u64),
+  /// List of source files
+  Sources,
+  /// Checksum of a mapped file (by inode) in the format of sha*sum
+  Checksum(u64, Checksum)
+}
+
+impl Content {
+  /// Whether the content is rendered by the filesystem on read
+  pub fn rendered(&self) -> bool {
+    matches!(self, Content::Status | Content::Describe(_) | Content::Sources | Content::Checksum(..))
+  }
+
+  /// Whether it's a mapped file (not internal files of the filesystem)
+  pub fn mapped(&self) -> bool {
+    matches!(self, Content::Range | Content::Synthetic | Content::Combined(_))
+  }
+}
+
+/// Encode device number in the format used by kernel (new_encode_dev)
+pub fn encode_dev(major: u32, minor: u32) -> u32 {
+  (minor & 0xff) | (major << 8) | ((minor & !0xff) << 12)
+}
+
+/// Source file with opened handle
+pub struct Source {
+  pub path: PathBuf,
+  /// Opened file (reopened when the path is replaced)
+  file: Option<fs::File>,
+  /// Device and inode number of opened file
+  id: (u64, u64),
+  /// Keep the file opened once instead of following the path
+  pinned: bool
+}
+
+impl Source {
+  pub fn new(path: PathBuf, pinned: bool) -> Self {
+    Self {
+      path,
+      file: None,
+      id: (0, 0),
+      pinned
+    }
+  }
+
+  /// Another handle to the same opened file
+  fn duplicate(&self) -> Self {
+    Self {
+      path: self.path.clone(),
+      file: self.file.as_ref().and_then(|f| f.try_clone().ok()),
+      id: self.id,
+      pinned: self.pinned
+    }
+  }
+
+  /// Reopen the file if it is replaced (e.g. by rename) or not opened yet
+  /// (pinned file is only opened once)
+  fn reopen(&mut self) {
+    if self.pinned && self.file.is_some() {
+      return;
+    }
+    match fs::metadata(&self.path) {
+      Ok(metadata) => {
+        let id = (metadata.dev(), metadata.ino());
+        if self.file.is_some() && self.id == id {
+          return;
+        }
+        if self.file.is_some() {
+          info!("Source file {:?} replaced, reopening", self.path);
+        }
+        self.file = fs::File::open(&self.path).ok();
+        self.id = id;
+      },
+      // don't keep deleted file
+      Err(_) => self.file = None
+    };
+  }
+
+  /// Whether [offset, offset + size) is entirely a hole
+  /// (always false if the filesystem doesn't support SEEK_DATA)
+  fn is_hole(&self, offset: u64, size: usize) -> bool {
+    let (Some(f), Ok(off)) = (&self.file, libc::off_t::try_from(offset)) else {
+      return false;
+    };
+    let data = unsafe { libc::lseek(f.as_raw_fd(), off, libc::SEEK_DATA) };
+    if data < 0 {
+      // ENXIO: no data after offset
+      return io::Error::last_os_error().raw_os_error() == Some(libc::ENXIO);
+    }
+    data as u64 >= offset.saturating_add(size as u64)
+  }
+
+  /// Metadata of the pinned file or the file at the path
+  fn metadata(&self) -> io::Result<fs::Metadata> {
+    match (&self.file, self.pinned) {
+      (Some(f), true) => f.metadata(),
+      _ => fs::metadata(&self.path)
+    }
+  }
+
+  /// Read data at offset (split into multiple reads of chunk size if specified)
+  fn read_at(&self, offset: u64, size: usize, chunk: Option<u64>) -> io::Result<Vec<u8>> {
+    let opened;
+    let f = match &self.file {
+      Some(f) => f,
+      None => {
+        opened = fs::File::open(&self.path)?;
+        &opened
+      }
+    };
+    let chunk = chunk.map_or(size, |c| c as usize).max(1);
+    let mut buf = vec![0; size];
+    let mut num = 0;
+    while num < size {
+      let len = cmp::min(chunk, size - num);
+      let n = match f.read_at(&mut buf[num..num + len], offset + num as u64) {
+        Ok(n) => n,
+        Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+        Err(err) => return Err(err)
+      };
+      num += n;
+      if n < len {
+        // end of file (possibly truncated since its size was read)
+        break;
+      }
+    }
+    buf.resize(num, 0);
+    Ok(buf)
+  }
 }
 
 // InodeInfo corresponds to top level dirs
@@ -35,24 +274,136 @@ pub struct InodeInfo {
   pub err: bool,
   /// Actual attr of the virtual file
   pub attr: FileAttr,
+  /// Resolved offset of the range in source file
+  pub offset: u64,
+  /// Resolved size of the range in source file
+  pub range_size: u64,
+  /// Size of the whole source
+  pub src_size: u64,
+  /// Cached content of the virtual file (e.g. transformed data)
+  data: Mutex<Option<Vec<u8>>>,
+  /// Cipher loaded from config
+  #[cfg(feature = "crypto")]
+  cipher: Option<AesCtr>,
+  /// Source files to read from
+  pub sources: Vec<Source>,
+  /// Counter for round-robin reads
+  next_source: AtomicUsize,
+  /// Cache of scanned ranges shared by all inodes
+  scan_cache: Arc<ScanCache>,
+  pub stats: Arc<InodeStats>,
+  pub content: Content,
   pub config: InodeConfig,
   /// Last update timestamp
-  timestamp: SystemTime
+  timestamp: SystemTime,
+  /// Generation of the inode number (bumped when the number is reused for another file)
+  pub generation: u64,
+  /// Version of the source (bumped when a change of its mtime or size is detected)
+  pub version: u64,
+  /// Mtime and size of source captured at mount (if snapshot is set)
+  snapshot: Option<(SystemTime, u64)>,
+  /// Time of the last read (if access time is tracked)
+  accessed: Option<SystemTime>
 }
 
 impl InodeInfo {
-  pub fn new(file: impl AsRef<Path>, ino: u64, config: InodeConfig) -> Self {
-    let (attr, err) = InodeInfo::get_metadata(file, ino, &config);
+  pub fn new(ino: u64, config: InodeConfig, scan_cache: Arc<ScanCache>) -> Self {
+    let sources = if config.synthetic.is_some() {
+      Vec::new()
+    } else {
+      config.sources.iter().map(|p| Source::new(p.clone(), config.pin)).collect()
+    };
+    let mut info = Self {
+      ino,
+      err: true,
+      attr: InodeInfo::dummy_attr(ino, &config),
+      offset: 0,
+      range_size: 0,
+      src_size: 0,
+      data: Mutex::new(None),
+      #[cfg(feature = "crypto")]
+      cipher: None,
+      sources,
+      next_source: AtomicUsize::new(0),
+      scan_cache,
+      stats: Arc::new(InodeStats::new()),
+      content: if config.synthetic.is_some() { Content::Synthetic } else { Content::Range },
+      config,
+      timestamp: SystemTime::now(),
+      generation: 0,
+      version: 0,
+      snapshot: None,
+      accessed: None
+    };
+    info.refresh();
+    // initial state of source
+    info.version = 0;
+    if info.config.snapshot {
+      info.snapshot = info.source_state();
+    }
+    info
+  }
+
+  /// Copy of resolved info without preload or cached content
+  /// (e.g. to read on demand while the original is stuck preloading)
+  pub fn without_preload(&self) -> Self {
+    Self {
+      ino: self.ino,
+      err: self.err,
+      attr: self.attr,
+      offset: self.offset,
+      range_size: self.range_size,
+      src_size: self.src_size,
+      data: Mutex::new(None),
+      #[cfg(feature = "crypto")]
+      cipher: self.cipher.clone(),
+      sources: self.sources.iter().map(Source::duplicate).collect(),
+      next_source: AtomicUsize::new(0),
+      scan_cache: self.scan_cache.clone(),
+      stats: Arc::new(InodeStats::new()),
+      content: self.content.clone(),
+      config: InodeConfig { preload: None, ..self.config.clone() },
+      timestamp: self.timestamp,
+      generation: self.generation,
+      version: self.version,
+      snapshot: self.snapshot,
+      accessed: self.accessed
+    }
+  }
+
+  /// Create info of a virtual file not reading source files directly
+  /// (its attr is maintained by the filesystem)
+  pub fn new_virtual(ino: u64, content: Content) -> Self {
+    let config = InodeConfig::default();
     Self {
       ino,
-      err,
-      attr,
+      err: false,
+      attr: InodeInfo::dummy_attr(ino, &config),
+      offset: 0,
+      range_size: 0,
+      src_size: 0,
+      data: Mutex::new(None),
+      #[cfg(feature = "crypto")]
+      cipher: None,
+      sources: Vec::new(),
+      next_source: AtomicUsize::new(0),
+      scan_cache: Arc::new(ScanCache::new(0)),
+      stats: Arc::new(InodeStats::new()),
+      content,
       config,
-      timestamp: SystemTime::now()
+      timestamp: SystemTime::now(),
+      generation: 0,
+      version: 0,
+      snapshot: None,
+      accessed: None
     }
   }
 
   pub fn outdated(&self, now: SystemTime, timeout: Duration) -> bool {
+    // zero timeout means never cache
+    if timeout.is_zero() {
+      return true;
+    }
     match now.duration_since(self.timestamp) {
       Ok(elapsed) => {
         // update if outdated
@@ -66,71 +417,538 @@ impl InodeInfo {
     }
   }
 
-  pub fn update_info(&mut self, file: impl AsRef<Path>, timeout: Duration) {
-    if self.outdated(SystemTime::now(), timeout) {
+  pub fn update_info(&mut self, timeout: Duration) {
+    // preloaded data is captured once (unless refresh_preload is set)
+    let frozen = self.config.preload.is_some() && !self.config.refresh_preload && self.preloaded()
+      || self.config.snapshot;
+    if matches!(self.content, Content::Range) && !frozen && self.outdated(SystemTime::now(), timeout) {
       debug!("Updating inode info");
-      let (attr, err) = InodeInfo::get_metadata(file, self.ino, &self.config);
-      self.attr = attr;
-      self.err = err;
-      self.timestamp = SystemTime::now();
+      let old = (self.attr.mtime, self.attr.size, self.offset);
+      let data = self.data.get_mut().unwrap_or_else(PoisonError::into_inner).take();
+      self.refresh();
+      if let (Some(limit), false) = (self.config.preload, self.err) {
+        match data {
+          // keep preloaded data if source is unchanged
+          Some(data) if old == (self.attr.mtime, self.attr.size, self.offset) => {
+            self.stats.cache_bytes.store(data.len() as u64, Ordering::Relaxed);
+            *self.cache() = Some(data);
+          },
+          _ => if let Err(err) = self.preload(limit) {
+            warn!("Error reloading preloaded data: {}", err);
+          }
+        };
+      }
     }
   }
 
-  // Get and derive attr from metadata of existing file
-  pub fn get_metadata(file: impl AsRef<Path>, ino: u64, config: &InodeConfig) -> (FileAttr, bool) {
+  /// Update access time of the virtual file (in memory only)
+  pub fn touch(&mut self) {
+    let now = SystemTime::now();
+    self.accessed = Some(now);
+    self.attr.atime = now;
+  }
+
+  /// Mtime and size of source (None if it can't be read)
+  fn source_state(&self) -> Option<(SystemTime, u64)> {
+    (!self.err).then_some((self.attr.mtime, self.src_size))
+  }
+
+  /// Check that the source is unchanged since mount (ESTALE if changed)
+  fn check_snapshot(&self) -> io::Result<()> {
+    let Some((mtime, size)) = self.snapshot else {
+      return Ok(());
+    };
+    let state = self.source_metadata().ok().map(|(m, src_size)| (m.modified().ok(), src_size));
+    if state != Some((Some(mtime), size)) {
+      return Err(io::Error::from_raw_os_error(ESTALE));
+    }
+    Ok(())
+  }
+
+  /// Re-read source file metadata and resolve the range
+  pub fn refresh(&mut self) {
+    let old_state = self.source_state();
+    // follow replaced source files
+    self.sources.iter_mut().for_each(Source::reopen);
+    match self.get_metadata() {
+      Ok((attr, offset, src_size)) => {
+        self.attr = attr;
+        self.offset = offset;
+        self.range_size = attr.size;
+        self.src_size = src_size;
+        self.err = false;
+      },
+      Err(err) => {
+        warn!("Error reading source file metadata: {}", err);
+        self.attr = InodeInfo::dummy_attr(self.ino, &self.config);
+        self.offset = 0;
+        self.range_size = 0;
+        self.src_size = 0;
+        self.err = true;
+      }
+    };
+    if self.source_state() != old_state {
+      self.version += 1;
+    }
+    // source atime isn't updated by reading the virtual file
+    if let Some(accessed) = self.accessed {
+      self.attr.atime = self.attr.atime.max(accessed);
+    }
+    // invalidate cache
+    *self.data.get_mut().unwrap_or_else(PoisonError::into_inner) = None;
+    self.stats.cache_bytes.store(0, Ordering::Relaxed);
+
+    #[cfg(feature = "crypto")]
+    if !self.err {
+      if let Some(key_source) = &self.config.aes_ctr_key {
+        // reload key as it may change
+        match AesCtr::load(key_source, self.config.aes_ctr_iv) {
+          Ok(cipher) => self.cipher = Some(cipher),
+          Err(err) => {
+            warn!("Error loading AES key: {}", err);
+            self.cipher = None;
+            self.err = true;
+          }
+        };
+      }
+    }
+
+    if !self.err && !self.config.transforms.is_empty() {
+      match self.transformed_size() {
+        Ok(size) => {
+          self.attr.size = size;
+          self.attr.blocks = size.div_ceil(512);
+        },
+        Err(err) => {
+          warn!("Error transforming source file: {}", err);
+          self.err = true;
+        }
+      };
+    }
+    self.timestamp = SystemTime::now();
+  }
+
+  /// Get and derive attr, offset and whole source size from metadata of source files
+  pub fn get_metadata(&self) -> io::Result<(FileAttr, u64, u64)> {
+    let ino = self.ino;
+    let config = &self.config;
+    if config.synthetic.is_some() {
+      // based on config only
+      let size = config.size.unwrap_or(0);
+      let attr = FileAttr {
+        size,
+        blocks: size.div_ceil(512),
+        perm: config.mode.unwrap_or(0o444) & !config.perm_mask,
+        ..InodeInfo::dummy_attr(ino, config)
+      };
+      return Ok((attr, 0, size));
+    }
     let cur_time = SystemTime::now();
-    match fs::metadata(file) {
-      Ok(src_metadata) => {
-        // permission bits (excluding the format bits)
-        let mut perm = src_metadata.mode() & !S_IFMT;
-        if src_metadata.is_dir() {
-          // remove executable bit
-          perm &= !(S_IXUSR | S_IXGRP | S_IXOTH);
+    let (src_metadata, src_size) = self.source_metadata()?;
+    // permission bits (excluding the format bits)
+    let mut perm = src_metadata.mode() & !S_IFMT;
+    if src_metadata.is_dir() {
+      // remove executable bit
+      perm &= !(S_IXUSR | S_IXGRP | S_IXOTH);
+    }
+    let (offset, size) = self.resolve_range(src_size, src_metadata.modified().ok())?;
+
+    Ok((FileAttr {
+      ino,
+      size,
+      blocks: size.div_ceil(512),
+      // Convert unix timestamp to SystemTime
+      atime: src_metadata.accessed().unwrap_or(cur_time),
+      mtime: src_metadata.modified().unwrap_or(cur_time),
+      ctime: src_metadata.accessed().unwrap_or(cur_time),
+      crtime: config.crtime.unwrap_or(src_metadata.created().unwrap_or(cur_time)), // macOS only
+      kind: config.kind.unwrap_or(FileType::RegularFile),
+      perm: config.mode.unwrap_or(perm as u16) & !config.perm_mask,
+      nlink: config.nlink.unwrap_or(1),
+      uid: config.uid.unwrap_or(src_metadata.uid()),
+      gid: config.gid.unwrap_or(src_metadata.gid()),
+      rdev: config.rdev.unwrap_or(0),
+      blksize: 512,
+      flags: 0 // macOS only
+    }, offset, src_size))
+  }
+
+  /// Get metadata of the first available source and size of the whole source
+  fn source_metadata(&self) -> io::Result<(fs::Metadata, u64)> {
+    if self.config.raid0.is_some() {
+      // all stripes are required
+      let mut metadata = self.sources.iter().map(Source::metadata).collect::<io::Result<Vec<_>>>()?;
+      let size = metadata.iter().map(|m| m.size()).sum();
+      return Ok((metadata.swap_remove(0), size));
+    }
+
+    let mut result = Err(io::Error::other("no source file"));
+    for src in self.sources.iter() {
+      result = src.metadata();
+      if result.is_ok() {
+        break;
+      }
+    }
+    let metadata = result?;
+    let size = metadata.size();
+    Ok((metadata, size))
+  }
+
+  /// Resolve offset and size of the range in source
+  fn resolve_range(&self, src_size: u64, mtime: Option<SystemTime>) -> io::Result<(u64, u64)> {
+    let config = &self.config;
+    if let Some(cmd) = &config.compute {
+      let (offset, size) = self.compute_range(cmd)?;
+      let size = if config.pad { size } else { cmp::min(size, src_size.saturating_sub(offset)) };
+      return Ok((offset, size));
+    }
+    if config.lines.is_some() || config.regex_start.is_some() || config.regex_end.is_some() || config.find.is_some() {
+      let key = ScanKey {
+        sources: self.sources.iter().map(|s| s.path.clone()).collect(),
+        params: ScanParams {
+          lines: config.lines,
+          regex_start: config.regex_start.as_ref().map(|r| r.as_str().into()),
+          regex_end: config.regex_end.as_ref().map(|r| r.as_str().into()),
+          find: config.find.clone(),
+          find_from: config.find_from,
+          size: config.size,
+          trailer: config.trailer,
+          pad: config.pad,
+          max_scan: config.max_scan,
+          raid0: config.raid0
         }
-        let size = config.size.unwrap_or(src_metadata.size().saturating_sub(config.offset.unwrap_or(0)));
-
-        (FileAttr {
-          ino,
-          size,
-          blocks: size.div_ceil(512),
-          // Convert unix timestamp to SystemTime
-          atime: src_metadata.accessed().unwrap_or(cur_time),
-          mtime: src_metadata.modified().unwrap_or(cur_time),
-          ctime: src_metadata.accessed().unwrap_or(cur_time),
-          crtime: src_metadata.created().unwrap_or(cur_time), // macOS only
-          kind: FileType::RegularFile,
-          perm: perm as u16,
-          nlink: 1,
-          uid: config.uid.unwrap_or(src_metadata.uid()),
-          gid: config.gid.unwrap_or(src_metadata.gid()),
-          rdev: 0,
-          blksize: 512,
-          flags: 0 // macOS only
-        }, false)
+      };
+      if let Some(range) = mtime.and_then(|t| self.scan_cache.get(&key, t, src_size)) {
+        return Ok(range);
       }
-      Err(err) => {
-        warn!("Error reading source file metadata: {}", err);
-        let size = config.size.unwrap_or(0);
-        // dummy attr
-        (FileAttr {
-          ino,
-          size: 0,
-          blocks: size.div_ceil(512),
-          // Convert unix timestamp to SystemTime
-          atime: cur_time,
-          mtime: cur_time,
-          ctime: cur_time,
-          crtime: cur_time, // macOS only
-          kind: FileType::RegularFile,
-          perm: 0o666,
-          nlink: 1,
-          uid: config.uid.unwrap_or(0),
-          gid: config.gid.unwrap_or(0),
-          rdev: 0,
-          blksize: 512,
-          flags: 0 // macOS only
-        }, true)
+      let reader = SourceReader { info: self, pos: 0 };
+      let range = if let Some((start, end)) = config.lines {
+        scan::line_range(reader, src_size, start, end, config.max_scan)?
+      } else if let Some(sig) = &config.find {
+        let reader = SourceReader { info: self, pos: config.find_from };
+        let offset = scan::find_signature(reader, src_size, sig, config.find_from, config.max_scan)?;
+        (offset, self.range_size_at(offset, src_size))
+      } else {
+        scan::regex_range(reader, src_size, config.regex_start.as_ref(), config.regex_end.as_ref(), config.max_scan)?
+      };
+      if let Some(t) = mtime {
+        self.scan_cache.insert(key, t, src_size, range);
+      }
+      return Ok(range);
+    }
+    let offset = config.offset.unwrap_or(0);
+    let size = self.range_size_at(offset, src_size);
+    if offset.checked_add(size).is_none() {
+      return Err(io::Error::other(format!("range overflows (offset: {}, size: {})", offset, size)));
+    }
+    Ok((offset, size))
+  }
+
+  /// Run the compute command to get offset and size of the range
+  fn compute_range(&self, cmd: &Path) -> io::Result<(u64, u64)> {
+    let mut child = Command::new(cmd)
+      .args(self.sources.iter().map(|s| &s.path))
+      .stdin(Stdio::null())
+      .stdout(Stdio::piped())
+      .stderr(Stdio::piped())
+      // own process group to kill processes it spawns as well
+      .process_group(0)
+      .spawn()
+      .map_err(|err| io::Error::new(err.kind(), format!("error running {:?}: {}", cmd, err)))?;
+    // read pipes in threads so a command filling them doesn't block
+    let read_pipe = |pipe: Option<Box<dyn Read + Send>>| {
+      let (tx, rx) = mpsc::channel();
+      thread::spawn(move || {
+        let mut buf = Vec::new();
+        if let Some(mut pipe) = pipe {
+          let _ = pipe.read_to_end(&mut buf);
+        }
+        let _ = tx.send(buf);
+      });
+      rx
+    };
+    let stdout = read_pipe(child.stdout.take().map(|p| Box::new(p) as _));
+    let stderr = read_pipe(child.stderr.take().map(|p| Box::new(p) as _));
+    // wait with a deadline as the command runs in the request loop
+    let deadline = Instant::now() + COMPUTE_TIMEOUT;
+    let status = loop {
+      if let Some(status) = child.try_wait()? {
+        break status;
       }
+      if Instant::now() >= deadline {
+        unsafe { libc::kill(-(child.id() as libc::pid_t), libc::SIGKILL) };
+        let _ = child.wait();
+        return Err(io::Error::new(io::ErrorKind::TimedOut, format!("{:?} timed out after {:?}", cmd, COMPUTE_TIMEOUT)));
+      }
+      thread::sleep(Duration::from_millis(10));
+    };
+    // pipes may be kept open by processes the command left behind
+    let remaining = || deadline.saturating_duration_since(Instant::now());
+    let stdout = stdout.recv_timeout(remaining())
+      .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, format!("output of {:?} not closed after {:?}", cmd, COMPUTE_TIMEOUT)))?;
+    let stderr = stderr.recv_timeout(remaining()).unwrap_or_default();
+    if !status.success() {
+      return Err(io::Error::other(format!(
+        "{:?} failed ({}): {}", cmd, status, String::from_utf8_lossy(&stderr).trim()
+      )));
+    }
+    let stdout = String::from_utf8_lossy(&stdout);
+    let invalid = || io::Error::other(format!("invalid output of {:?} (expected \"<offset> <size>\"): {:?}", cmd, stdout.trim()));
+    let mut fields = stdout.split_whitespace().map(str::parse::<u64>);
+    let (Some(Ok(offset)), Some(Ok(size)), None) = (fields.next(), fields.next(), fields.next()) else {
+      return Err(invalid());
+    };
+    if offset.checked_add(size).is_none() {
+      return Err(io::Error::other(format!("range overflows (offset: {}, size: {})", offset, size)));
     }
+    debug!("Computed range of inode {} by {:?}: offset {}, size {}", self.ino, cmd, offset, size);
+    Ok((offset, size))
+  }
+
+  /// Size of the range at offset (configured size limited to available data unless padded)
+  fn range_size_at(&self, offset: u64, src_size: u64) -> u64 {
+    let available = src_size.saturating_sub(offset);
+    match self.config.size {
+      Some(size) if self.config.pad => size,
+      Some(size) => cmp::min(size, available),
+      // recomputed as source grows and empty if the trailer covers the range
+      None => available.saturating_sub(self.config.trailer.unwrap_or(0))
+    }
+  }
+
+  /// Attr used when source file metadata can't be read
+  fn dummy_attr(ino: u64, config: &InodeConfig) -> FileAttr {
+    let cur_time = SystemTime::now();
+    let size = config.size.unwrap_or(0);
+    FileAttr {
+      ino,
+      size: 0,
+      blocks: size.div_ceil(512),
+      // Convert unix timestamp to SystemTime
+      atime: cur_time,
+      mtime: cur_time,
+      ctime: cur_time,
+      crtime: config.crtime.unwrap_or(cur_time), // macOS only
+      kind: config.kind.unwrap_or(FileType::RegularFile),
+      perm: config.mode.unwrap_or(0o666) & !config.perm_mask,
+      nlink: config.nlink.unwrap_or(1),
+      uid: config.uid.unwrap_or(0),
+      gid: config.gid.unwrap_or(0),
+      rdev: config.rdev.unwrap_or(0),
+      blksize: 512,
+      flags: 0 // macOS only
+    }
+  }
+
+  /// Size of transformed data (transformed and cached if not computable)
+  fn transformed_size(&self) -> io::Result<u64> {
+    if let Some(size) = transform::size_all(&self.config.transforms, self.range_size) {
+      return Ok(size);
+    }
+    let data = self.transform_range()?;
+    let size = data.len() as u64;
+    *self.cache() = Some(data);
+    self.stats.cache_bytes.store(size, Ordering::Relaxed);
+    Ok(size)
+  }
+
+  /// Read the whole range and apply transforms
+  fn transform_range(&self) -> io::Result<Vec<u8>> {
+    let size = usize::try_from(self.range_size).map_err(io::Error::other)?;
+    let data = self.read_range(0, size)?;
+    transform::apply_all(&self.config.transforms, data)
+  }
+
+  /// Read output of seekable transforms at offset by transforming only the needed window of input
+  fn read_transformed(&self, transforms: &[SharedTransform], offset: u64, size: u64) -> io::Result<Vec<u8>> {
+    let Some((last, rest)) = transforms.split_last() else {
+      let size = cmp::min(size, self.range_size.saturating_sub(offset));
+      return self.read_range(offset, usize::try_from(size).map_err(io::Error::other)?);
+    };
+    let (in_offset, in_size, pos) = last.input_window(offset, size)
+      .ok_or_else(|| io::Error::other("transform is not seekable"))?;
+    let data = last.apply(&self.read_transformed(rest, in_offset, in_size)?)?;
+    let start = cmp::min(pos, data.len() as u64) as usize;
+    let end = cmp::min(start + size as usize, data.len());
+    Ok(data[start..end].to_vec())
+  }
+
+  fn cache(&self) -> MutexGuard<'_, Option<Vec<u8>>> {
+    self.data.lock().unwrap_or_else(PoisonError::into_inner)
+  }
+
+  /// Load content into cache (up to limit bytes unless transforms are used)
+  pub fn preload(&self, limit: u64) -> io::Result<()> {
+    let data = if self.config.transforms.is_empty() {
+      let size = usize::try_from(cmp::min(limit, self.attr.size)).map_err(io::Error::other)?;
+      self.read_range(0, size)?
+    } else {
+      // transformed data can only be cached as a whole
+      self.transform_range()?
+    };
+    self.stats.cache_bytes.store(data.len() as u64, Ordering::Relaxed);
+    *self.cache() = Some(data);
+    Ok(())
+  }
+
+  /// Whether content is loaded into cache
+  pub fn preloaded(&self) -> bool {
+    self.cache().is_some()
+  }
+
+  /// Size of cached content
+  pub fn cached_bytes(&self) -> u64 {
+    self.stats.cache_bytes.load(Ordering::Relaxed)
+  }
+
+  /// Read data at offset of the virtual file
+  pub fn read_file(&self, offset: u64, size: u32) -> io::Result<Vec<u8>> {
+    self.check_snapshot()?;
+    if !self.config.transforms.is_empty() {
+      if transform::seekable(&self.config.transforms) && !self.preloaded() {
+        // only transform the data needed (nothing at or past EOF)
+        let size = cmp::min(self.attr.size.saturating_sub(offset), size as u64);
+        if size == 0 {
+          return Ok(Vec::new());
+        }
+        return self.read_transformed(&self.config.transforms, offset, size);
+      }
+      // transform the whole range and cache it
+      let mut cache = self.cache();
+      if cache.is_none() {
+        let data = self.transform_range()?;
+        self.stats.cache_bytes.store(data.len() as u64, Ordering::Relaxed);
+        *cache = Some(data);
+      }
+      let data = cache.as_deref().unwrap_or_default();
+      let start = cmp::min(offset, data.len() as u64) as usize;
+      let end = cmp::min(start + size as usize, data.len());
+      return Ok(data[start..end].to_vec());
+    }
+
+    let s = cmp::min(self.attr.size.saturating_sub(offset), size as u64);
+    if s == 0 {
+      // at or past EOF (no need to touch the source)
+      return Ok(Vec::new());
+    }
+    // serve from preloaded data if the window is within it
+    if let Some(data) = self.cache().as_deref() {
+      if offset.saturating_add(s) <= data.len() as u64 {
+        return Ok(data[offset as usize..(offset + s) as usize].to_vec());
+      }
+    }
+    let o = self.offset.checked_add(offset)
+      .ok_or_else(|| io::Error::other(format!("read offset overflows: {} + {}", self.offset, offset)))?;
+    let start = Instant::now();
+    let result = self.read_source(o, s as usize);
+    self.stats.latency.record(start.elapsed());
+    let mut data = result?;
+    if self.config.pad {
+      // zeros beyond the end of source
+      data.resize(s as usize, 0);
+    } else if data.is_empty() {
+      debug!("Source of inode {} truncated below offset {} in range (range offset: {})", self.ino, offset, self.offset);
+    } else if (data.len() as u64) < s {
+      debug!("Short read of inode {} at {}: {} of {} bytes (source truncated)", self.ino, offset, data.len(), s);
+    }
+    self.decrypt(offset, &mut data);
+    Ok(data)
+  }
+
+  /// Read data at offset in the range (before transforms)
+  fn read_range(&self, offset: u64, size: usize) -> io::Result<Vec<u8>> {
+    // resolved offsets (e.g. by find or compute) may be anywhere up to u64::MAX
+    let pos = self.offset.checked_add(offset)
+      .filter(|pos| pos.checked_add(size as u64).is_some())
+      .ok_or_else(|| io::Error::other(format!("read at offset {} of range at {} overflows", offset, self.offset)))?;
+    let mut data = self.read_source(pos, size)?;
+    if self.config.pad {
+      data.resize(size, 0);
+    }
+    self.decrypt(offset, &mut data);
+    Ok(data)
+  }
+
+  /// Decrypt data at offset in the range
+  fn decrypt(&self, offset: u64, data: &mut [u8]) {
+    if let Some(key) = &self.config.xor {
+      transform::xor_at(key, offset, data);
+    }
+    #[cfg(feature = "crypto")]
+    if let Some(cipher) = &self.cipher {
+      cipher.apply_at(offset, data);
+    }
+  }
+
+  /// Read data at offset of the whole source
+  fn read_source(&self, offset: u64, size: usize) -> io::Result<Vec<u8>> {
+    if let Some(synthetic) = &self.config.synthetic {
+      let size = cmp::min(size as u64, self.src_size.saturating_sub(offset)) as usize;
+      return Ok(synthetic.read(offset, size));
+    }
+    if let Some(width) = self.config.raid0 {
+      return self.read_raid0(width, offset, size);
+    }
+
+    let n = self.sources.len();
+    let first = match self.config.stripe {
+      Some(Stripe::RoundRobin) => self.next_source.fetch_add(1, Ordering::Relaxed) % n,
+      Some(Stripe::Offset) => (offset / STRIPE_BLOCK_SIZE % n as u64) as usize,
+      None => 0
+    };
+    // fall back to other sources on failure
+    let mut result = Err(io::Error::other("no source file"));
+    for i in 0..n {
+      let src = &self.sources[(first + i) % n];
+      if self.config.sparse && src.is_hole(offset, size) {
+        // skip reading holes
+        let size = cmp::min(size as u64, self.src_size.saturating_sub(offset)) as usize;
+        return Ok(vec![0; size]);
+      }
+      result = src.read_at(offset, size, self.config.read_chunk);
+      match &result {
+        Ok(_) => break,
+        Err(err) => warn!("Error reading source file {:?}: {}", src.path, err)
+      };
+    }
+    result
+  }
+
+  /// Read data from sources as stripes of a single file
+  fn read_raid0(&self, width: u64, offset: u64, size: usize) -> io::Result<Vec<u8>> {
+    let n = self.sources.len() as u64;
+    let mut buf = Vec::with_capacity(size);
+    let mut pos = offset;
+    // read stripe by stripe as a read may cross stripe boundaries
+    while buf.len() < size {
+      let stripe = pos / width;
+      let stripe_offset = pos % width;
+      let src = &self.sources[(stripe % n) as usize];
+      let len = cmp::min(width - stripe_offset, (size - buf.len()) as u64);
+      let data = src.read_at(stripe / n * width + stripe_offset, len as usize, self.config.read_chunk)?;
+      let num = data.len() as u64;
+      buf.extend(data);
+      if num < len {
+        // end of source
+        break;
+      }
+      pos += num;
+    }
+    Ok(buf)
+  }
+}
+
+/// Sequential reader of the whole source
+struct SourceReader<'a> {
+  info: &'a InodeInfo,
+  pos: u64
+}
+
+impl Read for SourceReader<'_> {
+  fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+    let data = self.info.read_source(self.pos, buf.len())?;
+    buf[..data.len()].copy_from_slice(&data);
+    self.pos += data.len() as u64;
+    Ok(data.len())
   }
 }
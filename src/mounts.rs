@@ -0,0 +1,90 @@
+// Copyright (C) 2023-2024  DCsunset
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::{fs, path::{Path, PathBuf}};
+use anyhow::{Result, anyhow, Context};
+use crate::{config::{DuplicateKey, config_line, parse_config}, metadata::InodeConfig};
+
+/// A mount point with its source file and mapped files
+pub struct MountConfig {
+  pub mount_point: PathBuf,
+  /// Default source file of mapped files
+  pub file: Option<PathBuf>,
+  pub configs: Vec<InodeConfig>
+}
+
+/// Parse a file listing multiple mounts.
+/// Each mount starts with a `[<mount_point>]` line,
+/// followed by an optional `file=<path>` line for its source file
+/// and one mapping config per line
+pub fn parse_config_file(path: impl AsRef<Path>, duplicate_key: DuplicateKey) -> Result<Vec<MountConfig>> {
+  let path = path.as_ref();
+  let content = fs::read_to_string(path)
+    .with_context(|| format!("error reading config file {:?}", path))?;
+  let mut mounts: Vec<MountConfig> = Vec::new();
+  for (i, line) in content.lines().enumerate() {
+    let err_context = || format!("invalid config file {:?} at line {}", path, i + 1);
+    let Some(line) = config_line(line) else {
+      continue;
+    };
+    if let Some(mount_point) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+      let mount_point = PathBuf::from(mount_point.trim());
+      if mount_point.as_os_str().is_empty() {
+        return Err(anyhow!("empty mount point")).with_context(err_context);
+      }
+      if mounts.iter().any(|m| m.mount_point == mount_point) {
+        return Err(anyhow!("duplicate mount point {:?}", mount_point)).with_context(err_context);
+      }
+      mounts.push(MountConfig { mount_point, file: None, configs: Vec::new() });
+      continue;
+    }
+    let mount = mounts.last_mut()
+      .ok_or_else(|| anyhow!("expected [<mount_point>] before configs"))
+      .with_context(err_context)?;
+    if let Some(file) = line.strip_prefix("file=") {
+      if mount.file.is_some() {
+        return Err(anyhow!("file specified more than once for {:?}", mount.mount_point)).with_context(err_context);
+      }
+      mount.file = Some(file.into());
+      continue;
+    }
+    mount.configs.push(parse_config(line, duplicate_key).with_context(err_context)?);
+  }
+  if mounts.is_empty() {
+    return Err(anyhow!("no mount point in config file {:?}", path));
+  }
+  if let Some(mount) = mounts.iter().find(|m| m.configs.is_empty()) {
+    return Err(anyhow!("no mapping config for mount point {:?} in config file {:?}", mount.mount_point, path));
+  }
+  Ok(mounts)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn comments_and_indentation() {
+    let path = std::env::temp_dir().join(format!("rangefs-{}-mounts.conf", std::process::id()));
+    fs::write(&path, "# mounts\n  [/mnt/a]  \n\t# c\n  name=a#b:size=1  \n#name=z\n").unwrap();
+    let mounts = parse_config_file(&path, DuplicateKey::Error).unwrap();
+    fs::remove_file(&path).unwrap();
+    assert_eq!(mounts.len(), 1);
+    assert_eq!(mounts[0].mount_point, Path::new("/mnt/a"));
+    let names: Vec<_> = mounts[0].configs.iter().map(|c| c.name.clone().unwrap()).collect();
+    assert_eq!(names, ["a#b"]);
+    assert_eq!(mounts[0].configs[0].size, Some(1));
+  }
+}
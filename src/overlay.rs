@@ -0,0 +1,102 @@
+// Copyright (C) 2023-2024  DCsunset
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::{cmp, collections::{BTreeMap, btree_map::Entry}, io};
+
+/// Size of each block stored in overlay
+pub const BLOCK_SIZE: u64 = 4096;
+
+/// In-memory copy-on-write overlay of a mapped file
+/// (modified blocks are stored here and the source is never written)
+pub struct Overlay {
+  /// Modified blocks by index
+  blocks: BTreeMap<u64, Vec<u8>>,
+  /// Size of the file after modification
+  pub size: u64,
+  /// Original data at or after this offset is discarded (by truncation)
+  base_limit: u64
+}
+
+impl Overlay {
+  pub fn new(base_size: u64) -> Self {
+    Self {
+      blocks: BTreeMap::new(),
+      size: base_size,
+      base_limit: base_size
+    }
+  }
+
+  /// Write data at offset (read_base reads original data to fill partially written blocks)
+  pub fn write(
+    &mut self,
+    offset: u64,
+    data: &[u8],
+    read_base: impl Fn(u64, u32) -> io::Result<Vec<u8>>
+  ) -> io::Result<()> {
+    if data.is_empty() {
+      return Ok(());
+    }
+    let end = offset.checked_add(data.len() as u64)
+      .ok_or_else(|| io::Error::other(format!("write offset overflows: {} + {}", offset, data.len())))?;
+    for b in offset / BLOCK_SIZE..=(end - 1) / BLOCK_SIZE {
+      let start = b * BLOCK_SIZE;
+      let block = match self.blocks.entry(b) {
+        Entry::Occupied(e) => e.into_mut(),
+        Entry::Vacant(e) => {
+          // copy original data on first write
+          let mut block = read_base(start, BLOCK_SIZE as u32)?;
+          block.truncate(self.base_limit.saturating_sub(start) as usize);
+          block.resize(BLOCK_SIZE as usize, 0);
+          e.insert(block)
+        }
+      };
+      let s = cmp::max(start, offset);
+      let e = cmp::min(start + BLOCK_SIZE, end);
+      block[(s - start) as usize..(e - start) as usize]
+        .copy_from_slice(&data[(s - offset) as usize..(e - offset) as usize]);
+    }
+    self.size = cmp::max(self.size, end);
+    Ok(())
+  }
+
+  /// Truncate or extend (with zeros) the file to size
+  pub fn truncate(&mut self, size: u64) {
+    self.base_limit = cmp::min(self.base_limit, size);
+    // drop blocks after size and zero the tail of the last one
+    self.blocks.retain(|b, _| b * BLOCK_SIZE < size);
+    if let Some(block) = self.blocks.get_mut(&(size / BLOCK_SIZE)) {
+      block[(size % BLOCK_SIZE) as usize..].fill(0);
+    }
+    self.size = size;
+  }
+
+  /// Merge modified blocks over original data read at offset
+  pub fn merge(&self, offset: u64, size: u32, mut base: Vec<u8>) -> Vec<u8> {
+    if offset >= self.size || size == 0 {
+      return Vec::new();
+    }
+    let end = cmp::min(offset.saturating_add(size as u64), self.size);
+    base.truncate(self.base_limit.saturating_sub(offset) as usize);
+    base.resize((end - offset) as usize, 0);
+    for (b, block) in self.blocks.range(offset / BLOCK_SIZE..=(end - 1) / BLOCK_SIZE) {
+      let start = b * BLOCK_SIZE;
+      let s = cmp::max(start, offset);
+      let e = cmp::min(start + BLOCK_SIZE, end);
+      base[(s - offset) as usize..(e - offset) as usize]
+        .copy_from_slice(&block[(s - start) as usize..(e - start) as usize]);
+    }
+    base
+  }
+}
@@ -13,136 +13,1490 @@
 // You should have received a copy of the GNU Affero General Public License
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
+use clap::ValueEnum;
 use fuser::{
   Filesystem,
+  FileAttr,
   FileType,
   Request,
   ReplyDirectory,
-  FUSE_ROOT_ID
+  ReplyXattr,
+  KernelConfig,
+  MountOption,
+  Notifier,
+  TimeOrNow,
+  FUSE_ROOT_ID,
+  consts::{FOPEN_DIRECT_IO, FUSE_POLL_SCHEDULE_NOTIFY}
 };
 use std::{
-  fs,
-  iter,
-  os::unix::prelude::FileExt,
-  time::{Duration, SystemTime}, ffi::{OsString, OsStr}, io,
-  collections::HashMap,
-  path::{Path, PathBuf}, cmp
+  iter, io::{self, Write}, cmp, fs, fmt::{Debug, Display},
+  time::{Duration, SystemTime, Instant, UNIX_EPOCH}, ffi::{OsString, OsStr},
+  os::unix::{fs::MetadataExt, ffi::OsStrExt},
+  sync::{Arc, Mutex, PoisonError, mpsc, atomic::Ordering}, thread, panic::{self, AssertUnwindSafe},
+  collections::{HashMap, HashSet, BTreeMap},
+  path::{Path, PathBuf}
 };
-use log::{error, warn};
-use crate::metadata::{InodeInfo, InodeConfig};
-use libc::{EIO, ENOENT};
+use log::{error, info, warn};
+use anyhow::{Result, anyhow};
+use crate::{checksum::Checksum, discover::DirSource, metadata::{InodeInfo, InodeConfig, Content}, overlay::Overlay, scan::ScanCache, stats::{json_string, FileStats, InodeStats}, synthetic::Synthetic, watch::{Pollers, WatchTargets}};
+use libc::{c_int, EEXIST, EIO, EINVAL, EISDIR, ENOENT, ENOTDIR, ENODATA, ERANGE, EROFS, ESTALE, O_ACCMODE, O_RDONLY, POLLIN, POLLRDNORM};
 
-pub struct RangeFs {
-  file: PathBuf,
+/// Name of the status file
+const STATUS_FILE: &str = ".status";
+/// Name of the introspection directory
+const INTROSPECT_DIR: &str = ".rangefs";
+
+/// Size of each read when reading whole files (e.g. saving modified files)
+const READ_CHUNK_SIZE: u64 = 1024 * 1024;
+
+/// Extended attribute of read latency summary
+const XATTR_LATENCY: &str = "user.rangefs.latency";
+/// Extended attribute of whether content is preloaded into cache
+const XATTR_PRELOADED: &str = "user.rangefs.preloaded";
+/// Extended attribute of size of cached content
+const XATTR_CACHED_BYTES: &str = "user.rangefs.cached_bytes";
+/// Extended attribute of version of the source (bumped when it changes)
+const XATTR_VERSION: &str = "user.rangefs.version";
+/// Capability to cache writes in kernel (exported by fuser only with abi-7-23)
+const FUSE_WRITEBACK_CACHE: u32 = 1 << 16;
+
+/// Order of files in listings
+#[derive(Clone, Copy, Debug, Default, PartialEq, ValueEnum)]
+pub enum OrderBy {
+  /// By name
+  #[default]
+  Name,
+  /// By resolved offset in source (files with default names are numbered in this order)
+  Offset
+}
+
+/// Options of the whole filesystem
+#[derive(Default, Clone)]
+pub struct FsOptions {
   /// Timeout for cache in fuse reply (attr, entry)
-  timeout: Duration,
-  // Map file name to inode
-  file_map: HashMap<OsString, u64>,
+  pub timeout: Duration,
+  /// Treat suspicious configs as errors
+  pub strict: bool,
+  /// Name of the file combining all mapped files
+  pub combined: Option<String>,
+  /// Show status file in readdir
+  pub show_status: bool,
+  /// Use mtime and ctime of the source file for root directory
+  pub root_time_from_source: bool,
+  /// Max number of cached line/regex scan results
+  pub scan_cache_size: usize,
+  /// Default owner of all files (overridden by config)
+  pub uid: Option<u32>,
+  pub gid: Option<u32>,
+  /// Serve the only mapped file at root (mount point is a file)
+  pub file_mount: bool,
+  /// Add introspection directory describing the mapped files
+  pub introspect: bool,
+  /// Max bytes to scan for all mapped files (capping max_scan in config)
+  pub max_scan: Option<u64>,
+  /// Max number of mapped files (unlimited if not set)
+  pub max_mappings: Option<usize>,
+  /// Keep source files opened at mount (surviving unlink and rotation)
+  pub pin_source: bool,
+  /// Present sources as they were at mount and fail reads with ESTALE if they change
+  pub snapshot: bool,
+  /// Max size of each read from source files
+  pub source_read_chunk: Option<u64>,
+  /// Reload preloaded data when outdated (instead of capturing once)
+  pub refresh_preload: bool,
+  /// Max time of preloading each mapped file at mount (read on demand after timeout)
+  pub preload_timeout: Option<Duration>,
+  /// Permission bits cleared from all mapped files
+  pub perm_mask: u16,
+  /// Bypass page cache for all files
+  pub direct_io: bool,
+  /// Fail reads extending past the end of file with EINVAL instead of short reads (implies direct_io)
+  pub strict_bounds: bool,
+  /// Order of files in listings and numbering of files with default names
+  pub order_by: OrderBy,
+  /// Update access time of mapped files on read (in memory only)
+  pub track_atime: bool,
+  /// Directory to discover mapped files from (rescanned when listed)
+  pub dir_source: Option<DirSource>,
+  /// Add a checksum file <name>.<algorithm> for each mapped file
+  pub checksum_sidecar: Option<Checksum>,
+  /// Max bytes the kernel reads ahead (default by kernel)
+  pub max_readahead: Option<u32>,
+  /// Let the kernel cache writes to the overlay (skipped if unsupported)
+  pub writeback_cache: bool,
+  /// Max number of pending background requests (e.g. readahead) in kernel (default by fuser)
+  pub max_background: Option<u16>,
+  /// Number of pending background requests at which the kernel considers the fs congested
+  pub congestion_threshold: Option<u16>,
+  /// Allow writes to mapped files stored in an in-memory overlay
+  pub overlay: bool,
+  /// Directory to save modified files on unmount (discarded if not set)
+  pub overlay_save: Option<PathBuf>,
+  /// Mount point and options (for logging only)
+  pub mount_point: PathBuf,
+  pub mount_options: Vec<MountOption>
+}
+
+pub struct RangeFs {
+  options: FsOptions,
+  /// Global source file (not needed if all mapped files have their own sources)
+  file: Option<PathBuf>,
+  /// Time when the filesystem is created
+  start_time: Instant,
+  // Map file name to inode (ordered by name for stable readdir)
+  file_map: BTreeMap<OsString, u64>,
   /// map inode to actual filename and metadata
-  inode_map: HashMap<u64, InodeInfo>
+  inode_map: HashMap<u64, InodeInfo>,
+  /// Poll handles waiting for changes
+  pollers: Pollers,
+  /// Size of each inode when last polled
+  polled_sizes: HashMap<u64, u64>,
+  /// Overlays of modified mapped files
+  overlays: HashMap<u64, Overlay>,
+  /// Lookup count of each inode referenced by the kernel
+  lookups: HashMap<u64, u64>,
+  /// Removed inodes kept until the kernel forgets them
+  removed: HashSet<u64>,
+  /// Mappings built by a reloader waiting to replace the current ones
+  pending: PendingMappings,
+  /// Number of times the mappings have been replaced
+  reloads: u64,
+  /// Next inode number for files added at runtime (never reused until a reload)
+  next_ino: u64,
+  /// Number of times each inode number has been assigned
+  /// (the generation of its next inode so reused numbers are told apart)
+  generations: HashMap<u64, u64>,
+  /// Snapshot of entries of each directory taken when its listing starts
+  /// (so later readdir calls index into a stable list by offset)
+  listings: HashMap<u64, Vec<(u64, FileType, OsString)>>,
+  /// Cached content of checksum files
+  checksums: Arc<ChecksumCache>,
+  /// Time when the source directory was last scanned
+  dir_scanned: Instant,
+  /// Stats of mapped files shared with the metrics writer
+  file_stats: FileStats,
+  /// Sources of mapped files shared with the watcher
+  watch_targets: WatchTargets,
+  /// Directory entries shared with the reloader
+  entries: Entries
+}
+
+/// File map and inode map built from configs
+type Mappings = (BTreeMap<OsString, u64>, HashMap<u64, InodeInfo>);
+
+/// State of a mapped file (mtime, size, version and generation) a checksum is computed from
+type ChecksumState = (SystemTime, u64, u64, u64);
+
+/// Content of a checksum file with the state it's computed from
+type ChecksumLine = (ChecksumState, Vec<u8>);
+
+/// Computation of a checksum run by the worker
+type ChecksumJob = Box<dyn FnOnce() + Send>;
+
+/// Content of checksum files by mapped inode, shared with the worker computing them
+#[derive(Default)]
+struct ChecksumCache {
+  lines: Mutex<HashMap<u64, ChecksumLine>>,
+  /// Queue of the worker thread (started on first use)
+  worker: Mutex<Option<mpsc::Sender<ChecksumJob>>>
+}
+
+impl ChecksumCache {
+  /// Cached checksum line of the state
+  fn get(&self, ino: u64, state: ChecksumState) -> Option<Vec<u8>> {
+    let lines = self.lines.lock().unwrap_or_else(PoisonError::into_inner);
+    lines.get(&ino).and_then(|(s, line)| (*s == state).then(|| line.clone()))
+  }
+
+  /// Run a computation on the worker
+  /// (one at a time so reads waiting for the same checksum reuse it instead of repeating it)
+  fn spawn(&self, job: ChecksumJob) {
+    let mut worker = self.worker.lock().unwrap_or_else(PoisonError::into_inner);
+    let tx = worker.get_or_insert_with(|| {
+      let (tx, rx) = mpsc::channel::<ChecksumJob>();
+      thread::spawn(move || for job in rx {
+        // a panicking computation only fails its own read
+        if panic::catch_unwind(AssertUnwindSafe(job)).is_err() {
+          error!("Checksum computation panicked");
+        }
+      });
+      tx
+    });
+    let _ = tx.send(job);
+  }
+
+  /// Cached checksum line of the state or compute it (on the worker)
+  fn get_or_compute(&self, ino: u64, state: ChecksumState, compute: impl FnOnce() -> io::Result<Vec<u8>>) -> io::Result<Vec<u8>> {
+    if let Some(line) = self.get(ino, state) {
+      return Ok(line);
+    }
+    let line = compute()?;
+    self.lines.lock().unwrap_or_else(PoisonError::into_inner).insert(ino, (state, line.clone()));
+    Ok(line)
+  }
+}
+
+/// Mappings waiting to be applied by the filesystem
+type PendingMappings = Arc<Mutex<Option<Mappings>>>;
+
+/// Entries (parent, name and inode) of all directories
+type Entries = Arc<Mutex<Vec<(u64, OsString, u64)>>>;
+
+/// Builds mappings from new configs to replace all mappings of a mounted filesystem
+pub struct Reloader {
+  file: Option<PathBuf>,
+  options: FsOptions,
+  pending: PendingMappings,
+  entries: Entries
 }
 
-impl Default for InodeConfig {
-  fn default() -> Self {
-    Self {
-      name: None,
-      offset: None,
-      size: None,
-      uid: None,
-      gid: None
+impl Reloader {
+  /// Build mappings from configs (same as at mount) and queue them to replace the current ones
+  /// (returns the number of mapped files).
+  /// With a notifier, kernel caches of the current mappings are invalidated
+  /// as inode numbers are reused by the new ones.
+  pub fn replace(&self, configs: Vec<InodeConfig>, notifier: Option<&Notifier>) -> Result<usize> {
+    let mappings = RangeFs::init_file_inode_map(&self.file, configs, &self.options)?;
+    // configs applied (excluding ignored duplicates and the combined file)
+    let count = mappings.0.values()
+      .filter(|ino| mappings.1.get(ino).is_some_and(|info| matches!(info.content, Content::Range | Content::Synthetic)))
+      .count();
+    let old = self.entries.lock().unwrap_or_else(PoisonError::into_inner).clone();
+    *self.pending.lock().unwrap_or_else(PoisonError::into_inner) = Some(mappings);
+    // queued first so requests after the invalidation see the new mappings
+    if let Some(notifier) = notifier {
+      invalidate(notifier, &old);
     }
+    Ok(count)
   }
 }
 
+/// Invalidate cached entries, attrs and data of inodes in kernel
+fn invalidate(notifier: &Notifier, entries: &[(u64, OsString, u64)]) {
+  // ENOENT if not cached by kernel
+  let check = |result: io::Result<()>, what: &dyn Debug| match result {
+    Err(err) if err.raw_os_error() != Some(ENOENT) => warn!("Error invalidating {:?} in kernel: {}", what, err),
+    _ => ()
+  };
+  for (parent, name, _) in entries {
+    check(notifier.inval_entry(*parent, name), name);
+  }
+  for (_, _, ino) in entries {
+    check(notifier.inval_inode(*ino, 0, 0), ino);
+  }
+}
+
+/// Permission bits of a newly created node (mode of the request with umask applied)
+fn created_perm(mode: u32, umask: u32) -> u16 {
+  // umask is 0 if the kernel has already applied it (without FUSE_DONT_MASK)
+  (mode & !umask & 0o7777) as u16
+}
+
+/// Preload content of a mapped file (on a separate thread if timeout is set)
+/// and fall back to reading on demand if it fails or times out
+fn preload(info: InodeInfo, limit: u64, timeout: Option<Duration>, name: &OsStr) -> InodeInfo {
+  let Some(timeout) = timeout else {
+    if let Err(err) = info.preload(limit) {
+      warn!("Error preloading mapped file {:?}: {}", name, err);
+    }
+    return info;
+  };
+  // resolved before preloading in case it gets stuck
+  let fallback = info.without_preload();
+  let info = Arc::new(info);
+  let worker = info.clone();
+  let (tx, rx) = mpsc::channel();
+  thread::spawn(move || {
+    let result = worker.preload(limit);
+    // release the info before sending so it can be unwrapped
+    drop(worker);
+    let _ = tx.send(result);
+  });
+  match rx.recv_timeout(timeout) {
+    Ok(result) => {
+      if let Err(err) = result {
+        warn!("Error preloading mapped file {:?}: {}", name, err);
+      }
+      if let Ok(info) = Arc::try_unwrap(info) {
+        return info;
+      }
+    },
+    Err(_) => warn!("Preloading mapped file {:?} timed out after {:?}; reading on demand instead", name, timeout)
+  };
+  // the stuck read keeps the original info
+  fallback
+}
+
+/// Apply options of the filesystem to config of a mapped file
+fn apply_options(config: &mut InodeConfig, options: &FsOptions) {
+  config.uid = config.uid.or(options.uid);
+  config.gid = config.gid.or(options.gid);
+  config.pin |= options.pin_source;
+  config.snapshot = options.snapshot;
+  config.read_chunk = options.source_read_chunk;
+  config.refresh_preload = options.refresh_preload;
+  config.perm_mask = options.perm_mask;
+  config.max_scan = match (config.max_scan, options.max_scan) {
+    (Some(a), Some(b)) => Some(cmp::min(a, b)),
+    (a, b) => a.or(b)
+  };
+}
+
+/// Part of data at offset with at most size bytes
+fn slice_at(data: &[u8], offset: u64, size: u32) -> Vec<u8> {
+  let start = cmp::min(offset, data.len() as u64) as usize;
+  let end = cmp::min(start + size as usize, data.len());
+  data[start..end].to_vec()
+}
+
+/// Line of checksum file in the format of sha*sum ("<digest>  <name>")
+fn checksum_line(digest: &str, name: &OsStr) -> Vec<u8> {
+  let mut line = format!("{}  ", digest).into_bytes();
+  line.extend_from_slice(name.as_bytes());
+  line.push(b'\n');
+  line
+}
+
+/// Whether a read starts within the file but extends past its end
+fn straddles_end(offset: u64, size: u32, file_size: u64) -> bool {
+  offset < file_size && offset.saturating_add(size as u64) > file_size
+}
+
+/// Limit of background requests applied by the kernel
+/// (capped by /proc/sys/fs/fuse/<sysctl> if not mounted by root)
+fn background_limit(value: u16, sysctl: &str) -> u16 {
+  if unsafe { libc::geteuid() } == 0 {
+    return value;
+  }
+  fs::read_to_string(Path::new("/proc/sys/fs/fuse").join(sysctl)).ok()
+    .and_then(|max| max.trim().parse::<u16>().ok())
+    .map_or(value, |max| cmp::min(value, max))
+}
+
+/// Apply a kernel setting, retrying with the nearest value the kernel accepts
+/// (returns the applied value or None if left at default)
+fn negotiate<T: Copy + Display>(name: &str, value: T, mut set: impl FnMut(T) -> Result<T, T>) -> Option<T> {
+  let Err(nearest) = set(value) else {
+    return Some(value);
+  };
+  warn!("Kernel rejected {} {}; using nearest value {}", name, value, nearest);
+  match set(nearest) {
+    Ok(_) => Some(nearest),
+    Err(_) => {
+      warn!("Kernel rejected {} {}; using default", name, nearest);
+      None
+    }
+  }
+}
+
+/// Enable a kernel capability if supported (returns whether it's enabled)
+fn enable_capability<E>(name: &str, add: impl FnOnce() -> Result<(), E>) -> bool {
+  let enabled = add().is_ok();
+  if !enabled {
+    warn!("Kernel doesn't support {}; continuing without it", name);
+  }
+  enabled
+}
+
+/// Generation of an inode number being assigned (counting its assignments)
+fn next_generation(generations: &mut HashMap<u64, u64>, ino: u64) -> u64 {
+  let count = generations.entry(ino).or_default();
+  *count += 1;
+  *count - 1
+}
+
+/// Unique name of a file to be numbered (NUL can't be in real names)
+fn numbered_placeholder(name: &OsStr, ino: u64) -> OsString {
+  let mut name = name.to_os_string();
+  name.push(format!("\0{}", ino));
+  name
+}
+
+/// Link count of a directory (2 plus the number of subdirectories)
+fn dir_nlink(entries: &BTreeMap<OsString, u64>, inode_map: &HashMap<u64, InodeInfo>) -> u32 {
+  let subdirs = entries.values()
+    .filter(|ino| inode_map.get(ino).is_some_and(|info| matches!(info.content, Content::Dir(..))))
+    .count();
+  2 + subdirs as u32
+}
+
 impl RangeFs {
-  pub fn new(file: PathBuf, configs: Vec<InodeConfig>, timeout_secs: u64) -> Self {
-    let (file_map, inode_map) = RangeFs::init_file_inode_map(&file, configs);
-    Self {
+  pub fn new(file: Option<PathBuf>, configs: Vec<InodeConfig>, options: FsOptions) -> Result<Self> {
+    let (file_map, inode_map) = RangeFs::init_file_inode_map(&file, configs, &options)?;
+    let mut fs = Self {
+      options,
       file,
-      timeout: Duration::from_secs(timeout_secs),
+      start_time: Instant::now(),
       file_map,
-      inode_map
+      inode_map,
+      pollers: Pollers::default(),
+      polled_sizes: HashMap::new(),
+      overlays: HashMap::new(),
+      lookups: HashMap::new(),
+      removed: HashSet::new(),
+      pending: PendingMappings::default(),
+      reloads: 0,
+      next_ino: FUSE_ROOT_ID + 1,
+      generations: HashMap::new(),
+      listings: HashMap::new(),
+      checksums: Arc::default(),
+      dir_scanned: Instant::now(),
+      file_stats: FileStats::default(),
+      watch_targets: WatchTargets::default(),
+      entries: Entries::default()
+    };
+    fs.assign_generations();
+    fs.publish();
+    Ok(fs)
+  }
+
+  /// Reloader to replace all mappings at runtime
+  pub fn reloader(&self) -> Reloader {
+    Reloader {
+      file: self.file.clone(),
+      options: self.options.clone(),
+      pending: self.pending.clone(),
+      entries: self.entries.clone()
+    }
+  }
+
+  /// Update state shared with other threads after mappings change
+  fn publish(&self) {
+    let stats = self.mapped_files().into_iter()
+      .map(|(name, info)| (name.to_string_lossy().into_owned(), info.stats.clone()))
+      .collect();
+    *self.file_stats.lock().unwrap_or_else(PoisonError::into_inner) = stats;
+    let paths = |ino| self.inode_map.get(ino).into_iter().flat_map(|info: &InodeInfo| info.sources.iter().map(|s| s.path.clone()));
+    let targets = self.inode_map.values().filter_map(|info| match &info.content {
+      Content::Range => Some((info.ino, paths(&info.ino).collect())),
+      Content::Combined(segments) => Some((info.ino, segments.iter().flat_map(paths).collect())),
+      _ => None
+    }).collect();
+    *self.watch_targets.lock().unwrap_or_else(PoisonError::into_inner) = targets;
+    let root = self.file_map.iter().map(|(name, ino)| (FUSE_ROOT_ID, name.clone(), *ino));
+    let subdirs = self.inode_map.values().flat_map(|info| match &info.content {
+      Content::Dir(_, entries) => entries.iter().map(|(name, ino)| (info.ino, name.clone(), *ino)).collect(),
+      _ => Vec::new()
+    });
+    *self.entries.lock().unwrap_or_else(PoisonError::into_inner) = root.chain(subdirs).collect();
+  }
+
+  /// Replace all mappings with the pending ones if any
+  /// (called at the start of requests so each request sees either the old or the new set)
+  fn apply_pending(&mut self) {
+    let Some((file_map, inode_map)) = self.pending.lock().unwrap_or_else(PoisonError::into_inner).take() else {
+      return;
+    };
+    self.reloads += 1;
+    info!("Replaced mappings (reload {})", self.reloads);
+    self.file_map = file_map;
+    self.inode_map = inode_map;
+    self.assign_generations();
+    self.polled_sizes.clear();
+    self.overlays.clear();
+    // lookup counts are kept as the kernel references old inodes (by number) until it forgets them,
+    // while removed inodes are gone with the old mappings (and their numbers may be reused)
+    self.removed.clear();
+    self.listings.clear();
+    self.checksums.lines.lock().unwrap_or_else(PoisonError::into_inner).clear();
+    self.publish();
+  }
+
+  /// Assign generations to all inodes (after the inode map is replaced)
+  fn assign_generations(&mut self) {
+    for info in self.inode_map.values_mut() {
+      info.generation = next_generation(&mut self.generations, info.ino);
     }
+    let max = self.inode_map.keys().max().copied().unwrap_or(FUSE_ROOT_ID);
+    self.next_ino = cmp::max(self.next_ino, max + 1);
+  }
+
+  /// Allocate an inode number for a file added at runtime (returns the number and its generation)
+  fn alloc_ino(&mut self) -> (u64, u64) {
+    let ino = self.next_ino;
+    self.next_ino += 1;
+    (ino, next_generation(&mut self.generations, ino))
   }
 
   /// Init file_map and inode_map
-  fn init_file_inode_map(file: impl AsRef<Path>, configs: Vec<InodeConfig>) -> (HashMap<OsString, u64>, HashMap<u64, InodeInfo>) {
-    let mut file_map: HashMap<OsString, _> = HashMap::new();
+  fn init_file_inode_map(file: &Option<PathBuf>, mut configs: Vec<InodeConfig>, options: &FsOptions) -> Result<Mappings> {
+    let mut file_map: BTreeMap<OsString, _> = BTreeMap::new();
     let mut inode_map = HashMap::new();
+    if let Some(dir_source) = &options.dir_source {
+      configs.extend(dir_source.configs()?);
+    }
+    if let Some(max) = options.max_mappings {
+      if configs.len() > max {
+        return Err(anyhow!("too many mappings: {} requested but the limit is {} (see --max-mappings)", configs.len(), max));
+      }
+    }
+    let scan_cache = Arc::new(ScanCache::new(options.scan_cache_size));
 
+    // next ino available after mapped files
+    let mut next_ino = 2 + configs.len() as u64;
+    let mut pending = Vec::new();
+    // files to be numbered by offset (default name and ino)
+    let mut numbered = Vec::new();
     // ino start fro 2 as 1 is reserved for FUSE root directory
-    for (ino, config) in iter::zip(2.., configs) {
+    for (ino, mut config) in iter::zip(2.., configs) {
+      apply_options(&mut config, options);
+      // use global source file by default
+      if config.sources.is_empty() && config.synthetic.is_none() {
+        let Some(file) = file else {
+          return Err(anyhow!("source file is required by mapping {}", config.name.as_deref().unwrap_or("without name")));
+        };
+        config.sources.push(file.clone());
+      }
       // use original device name as default name if not specified
-      // let name = n.unwrap_or(path).as_os_str().to_os_string();
-      let name: OsString = match &config.name {
-        Some(name) => name.into(),
-        None => {
-          file.as_ref().file_name()
-            .expect(&format!("invalid source file: {:?}", file.as_ref()))
+      let name: OsString = match (&config.name, config.sources.first()) {
+        (Some(name), _) => name.into(),
+        (None, Some(src)) => {
+          src.file_name()
+            .ok_or_else(|| anyhow!("invalid source file (no file name to use as default name): {:?}", src))?
             .into()
+        },
+        (None, None) => return Err(anyhow!("name is required for mapping without source file"))
+      };
+      // keyed by a unique placeholder until offsets are resolved
+      let name = if options.order_by == OrderBy::Offset && config.name.is_none() {
+        numbered.push((name.clone(), ino));
+        numbered_placeholder(&name, ino)
+      } else {
+        name
+      };
+      if pending.iter().any(|(_, n, _)| *n == name) {
+        warn!("Ignoring config with duplicate name: {:?}", name);
+      } else {
+        pending.push((ino, name, config));
+      }
+    }
+
+    // resolve configs after the ones they depend on
+    while !pending.is_empty() {
+      let count = pending.len();
+      let mut unresolved = Vec::new();
+      for (ino, name, mut config) in pending.drain(..) {
+        if let Some(after) = &config.after {
+          match file_map.get(OsStr::new(after)) {
+            Some(dep_ino) => {
+              let dep: &InodeInfo = &inode_map[dep_ino];
+              if dep.err {
+                warn!("Mapped file {:?} is after {:?} with errors", name, after);
+              }
+              // size of the range in source (not of the transformed data)
+              let offset = dep.offset.checked_add(dep.range_size)
+                .ok_or_else(|| anyhow!("offset of mapped file {:?} after {:?} overflows", name, after))?;
+              config.offset = Some(offset);
+            },
+            None => {
+              unresolved.push((ino, name, config));
+              continue;
+            }
+          };
+        }
+
+        let info = InodeInfo::new(ino, config, scan_cache.clone());
+        // likely a misconfigured offset
+        if !info.err && info.attr.size == 0 && info.src_size > 0 {
+          let msg = format!("Mapped file {:?} is empty (offset: {})", name, info.offset);
+          if options.strict {
+            return Err(anyhow!(msg));
+          }
+          warn!("{}", msg);
+        }
+        let info = match (info.config.preload, info.err) {
+          (Some(limit), false) => preload(info, limit, options.preload_timeout, &name),
+          _ => info
+        };
+        inode_map.insert(ino, info);
+        file_map.insert(name, ino);
+      }
+
+      // no progress means unknown names or cycles
+      if unresolved.len() == count {
+        let names: Vec<_> = unresolved.iter().map(|(_, name, _)| name).collect();
+        return Err(anyhow!("unknown or cyclic after option in mapped files: {:?}", names));
+      }
+      pending = unresolved;
+    }
+
+    // number files with default names of each source by resolved offset (then config order)
+    let mut groups: BTreeMap<OsString, Vec<u64>> = BTreeMap::new();
+    for (name, ino) in numbered {
+      groups.entry(name).or_default().push(ino);
+    }
+    for (base, mut inos) in groups {
+      inos.sort_by_key(|ino| (inode_map[ino].offset, *ino));
+      let width = (inos.len() - 1).to_string().len();
+      for (i, ino) in inos.into_iter().enumerate() {
+        file_map.remove(&numbered_placeholder(&base, ino));
+        let mut name = base.clone();
+        name.push(format!(".{:0width$}", i));
+        if file_map.contains_key(&name) {
+          return Err(anyhow!("numbered name conflicts with mapped file: {:?}", name));
+        }
+        file_map.insert(name, ino);
+      }
+    }
+
+    if let Some(name) = &options.combined {
+      if file_map.contains_key(OsStr::new(name)) {
+        return Err(anyhow!("name of combined file conflicts with mapped file: {}", name));
+      }
+      // in config order
+      let mut segments: Vec<_> = inode_map.keys().copied().collect();
+      segments.sort();
+      let ino = next_ino;
+      next_ino += 1;
+      inode_map.insert(ino, InodeInfo::new_virtual(ino, Content::Combined(segments)));
+      file_map.insert(name.into(), ino);
+    }
+
+    if let Some(checksum) = options.checksum_sidecar {
+      if options.file_mount {
+        return Err(anyhow!("checksum files require a directory as mount point"));
+      }
+      let mapped: Vec<_> = file_map.iter()
+        .filter(|(_, ino)| inode_map.get(*ino).is_some_and(|info: &InodeInfo| info.content.mapped()))
+        .map(|(name, ino)| (name.clone(), *ino))
+        .collect();
+      for (name, ino) in mapped {
+        let mut checksum_name = name.clone();
+        checksum_name.push(".");
+        checksum_name.push(checksum.extension());
+        if file_map.contains_key(&checksum_name) {
+          return Err(anyhow!("name of checksum file conflicts with mapped file: {:?}", checksum_name));
         }
+        let mut info = InodeInfo::new_virtual(next_ino, Content::Checksum(ino, checksum));
+        // size is known without computing the digest
+        let size = checksum_line("", &name).len() as u64 + checksum.hex_len() as u64;
+        info.attr.size = size;
+        info.attr.blocks = size.div_ceil(512);
+        inode_map.insert(next_ino, info);
+        file_map.insert(checksum_name, next_ino);
+        next_ino += 1;
+      }
+    }
+
+    // reserved status file
+    if file_map.contains_key(OsStr::new(STATUS_FILE)) {
+      return Err(anyhow!("name {} is reserved for status file", STATUS_FILE));
+    }
+    inode_map.insert(next_ino, InodeInfo::new_virtual(next_ino, Content::Status));
+    file_map.insert(STATUS_FILE.into(), next_ino);
+    next_ino += 1;
+
+    if options.introspect {
+      if file_map.contains_key(OsStr::new(INTROSPECT_DIR)) {
+        return Err(anyhow!("name {} is reserved for introspection directory", INTROSPECT_DIR));
+      }
+      let mapped: Vec<_> = file_map.iter()
+        .filter(|(_, ino)| inode_map.get(*ino).is_some_and(|info: &InodeInfo| info.content.mapped()))
+        .map(|(name, ino)| (name.clone(), *ino))
+        .collect();
+      // reserved first as the parent of subdirectories
+      let dir_ino = next_ino;
+      next_ino += 1;
+      let mut new_inode = |content| {
+        let ino = next_ino;
+        next_ino += 1;
+        inode_map.insert(ino, InodeInfo::new_virtual(ino, content));
+        ino
       };
-      match file_map.get(&name) {
-        Some(_) => warn!("Ignoring config with duplicate name: {:?}", name),
-        None => {
-          let info = InodeInfo::new(&file, ino, config);
-          inode_map.insert(ino, info);
-          file_map.insert(name, ino);
+      // .rangefs/mappings/<name> describes each mapped file
+      let mappings: BTreeMap<_, _> = mapped.into_iter()
+        .map(|(name, ino)| (name, new_inode(Content::Describe(ino))))
+        .collect();
+      let mappings_ino = new_inode(Content::Dir(dir_ino, mappings));
+      let sources_ino = new_inode(Content::Sources);
+      let dir = BTreeMap::from([
+        (OsString::from("mappings"), mappings_ino),
+        (OsString::from("sources"), sources_ino)
+      ]);
+      inode_map.insert(dir_ino, InodeInfo::new_virtual(dir_ino, Content::Dir(FUSE_ROOT_ID, dir)));
+      file_map.insert(INTROSPECT_DIR.into(), dir_ino);
+    }
+
+    // set attr of directories
+    let nlinks: HashMap<_, _> = inode_map.values()
+      .filter_map(|info| match &info.content {
+        Content::Dir(_, entries) => Some((info.ino, dir_nlink(entries, &inode_map))),
+        _ => None
+      })
+      .collect();
+    for info in inode_map.values_mut().filter(|info| matches!(info.content, Content::Dir(..))) {
+      info.attr = FileAttr {
+        kind: FileType::Directory,
+        perm: 0o555,
+        nlink: nlinks[&info.ino],
+        uid: options.uid.unwrap_or(0),
+        gid: options.gid.unwrap_or(0),
+        ..info.attr
+      };
+    }
+    Ok((file_map, inode_map))
+  }
+
+  /// Update info of inode (and the mapped files it depends on)
+  fn update(&mut self, ino: u64) -> Option<&InodeInfo> {
+    self.update_with_timeout(ino, self.options.timeout)
+  }
+
+  /// Update info of inode if it's older than timeout
+  fn update_with_timeout(&mut self, ino: u64, timeout: Duration) -> Option<&InodeInfo> {
+    let info = self.inode_map.get(&ino)?;
+    let segments = match &info.content {
+      Content::Range | Content::Synthetic => None,
+      Content::Combined(segments) => Some(segments.clone()),
+      Content::Dir(..) => return self.inode_map.get(&ino),
+      Content::Checksum(target, _) => {
+        let target = *target;
+        let (attr, err) = self.update(target).map(|t| (t.attr, t.err))?;
+        let info = self.inode_map.get_mut(&ino)?;
+        info.err = err;
+        info.attr = FileAttr {
+          atime: attr.atime,
+          mtime: attr.mtime,
+          ctime: attr.ctime,
+          perm: attr.perm & 0o444,
+          uid: attr.uid,
+          gid: attr.gid,
+          ..info.attr
+        };
+        return Some(info);
+      },
+      Content::Status | Content::Describe(_) | Content::Sources => {
+        let size = self.render(info).len() as u64;
+        let cur_time = SystemTime::now();
+        let info = self.inode_map.get_mut(&ino)?;
+        info.attr = FileAttr {
+          size,
+          blocks: size.div_ceil(512),
+          atime: cur_time,
+          mtime: cur_time,
+          ctime: cur_time,
+          perm: 0o444,
+          uid: self.options.uid.unwrap_or(0),
+          gid: self.options.gid.unwrap_or(0),
+          ..info.attr
+        };
+        return Some(info);
+      }
+    };
+    let Some(segments) = segments else {
+      let info = self.inode_map.get_mut(&ino)?;
+      info.update_info(timeout);
+      return Some(info);
+    };
+
+    let mut attr: Option<FileAttr> = None;
+    let mut err = false;
+    for seg in segments {
+      let Some(seg_info) = self.inode_map.get_mut(&seg) else { continue };
+      seg_info.update_info(timeout);
+      err |= seg_info.err;
+      match &mut attr {
+        // use attr of the first mapped file by default
+        None => attr = Some(seg_info.attr),
+        Some(a) => {
+          a.size = a.size.saturating_add(seg_info.attr.size);
+          a.mtime = cmp::max(a.mtime, seg_info.attr.mtime);
+          a.ctime = cmp::max(a.ctime, seg_info.attr.ctime);
         }
       };
     }
-    (file_map, inode_map)
+    let info = self.inode_map.get_mut(&ino)?;
+    info.err = err;
+    if let Some(a) = attr {
+      info.attr = FileAttr {
+        ino,
+        blocks: a.size.div_ceil(512),
+        kind: FileType::RegularFile,
+        rdev: 0,
+        ..a
+      };
+    }
+    Some(info)
   }
-}
 
+  /// Render content of internal files
+  fn render(&self, info: &InodeInfo) -> String {
+    match &info.content {
+      Content::Status => self.render_status(),
+      Content::Describe(ino) => self.render_description(*ino),
+      Content::Sources => self.render_sources(),
+      _ => String::new()
+    }
+  }
 
-impl Filesystem for RangeFs {
-  fn lookup(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: fuser::ReplyEntry) {
-    // Only one root directory
-    if parent != FUSE_ROOT_ID {
+  /// Reply a read of the checksum file of a mapped file
+  /// (computed by the worker on first read and cached until the mapped file changes)
+  fn read_checksum(&mut self, ino: u64, checksum: Checksum, stats: Arc<InodeStats>, offset: u64, size: u32, reply: fuser::ReplyData) {
+    // refreshed regardless of timeout so a changed source isn't served a stale digest
+    self.update_with_timeout(ino, Duration::ZERO);
+    let (Some(info), Some(name)) = (self.inode_map.get(&ino), self.file_map.iter().find_map(|(name, i)| (*i == ino).then_some(name))) else {
       reply.error(ENOENT);
       return;
+    };
+    let version = match &info.content {
+      Content::Combined(segments) => segments.iter().filter_map(|seg| self.inode_map.get(seg)).map(|i| i.version).sum(),
+      _ => info.version
+    };
+    let state = (info.attr.mtime, info.attr.size, version, info.generation);
+    if let Some(line) = self.checksums.get(ino, state) {
+      let data = slice_at(&line, offset, size);
+      stats.record_read(data.len());
+      reply.data(&data);
+      return;
     }
-    match self.file_map.get(name) {
-      Some(ino) => {
-        let info = self.inode_map.get_mut(ino).expect(&format!("invalid ino: {}", ino));
-        info.update_info(&self.file, self.timeout);
-        reply.entry(&self.timeout, &info.attr, 0);
+    // read by a separate copy of the mapped file (or of each segment) so the request loop isn't blocked
+    let configs: Vec<_> = match &info.content {
+      Content::Combined(segments) => segments.iter().filter_map(|seg| self.inode_map.get(seg)).map(|i| (i.ino, i.config.clone())).collect(),
+      _ => vec![(ino, info.config.clone())]
+    };
+    let name = name.clone();
+    let checksums = self.checksums.clone();
+    self.checksums.spawn(Box::new(move || {
+      let result = checksums.get_or_compute(ino, state, || {
+        let scan_cache = Arc::new(ScanCache::new(0));
+        let mut hasher = checksum.hasher();
+        for (ino, config) in configs {
+          let info = InodeInfo::new(ino, InodeConfig { preload: None, ..config }, scan_cache.clone());
+          if info.err {
+            return Err(io::Error::other(format!("error reading inode {}", ino)));
+          }
+          let mut offset = 0;
+          while offset < info.attr.size {
+            let size = cmp::min(info.attr.size - offset, READ_CHUNK_SIZE) as u32;
+            let data = info.read_file(offset, size)?;
+            if data.is_empty() {
+              // shrunk while reading
+              break;
+            }
+            hasher.update(&data);
+            offset += data.len() as u64;
+          }
+        }
+        Ok(checksum_line(&hasher.finish(), &name))
+      });
+      match result {
+        Ok(line) => {
+          let data = slice_at(&line, offset, size);
+          stats.record_read(data.len());
+          reply.data(&data);
+        },
+        Err(err) => {
+          stats.record_error();
+          error!("Error computing checksum of inode {}: {}", ino, err);
+          reply.error(EIO);
+        }
+      };
+    }));
+  }
+
+  /// Mapped files with names sorted by inode
+  fn mapped_files(&self) -> Vec<(&OsString, &InodeInfo)> {
+    let mut files: Vec<_> = self.file_map.iter()
+      .filter_map(|(name, ino)| Some((name, self.inode_map.get(ino)?)))
+      .filter(|(_, info)| info.content.mapped())
+      .collect();
+    files.sort_by_key(|(_, info)| info.ino);
+    files
+  }
+
+  /// Render description of a mapped file in lines of key=value
+  fn render_description(&self, ino: u64) -> String {
+    let Some((name, info)) = self.mapped_files().into_iter().find(|(_, info)| info.ino == ino) else {
+      return String::new();
+    };
+    let mut lines = vec![format!("name={}", name.to_string_lossy())];
+    match &info.content {
+      Content::Combined(segments) => {
+        let names: Vec<_> = self.mapped_files().into_iter()
+          .filter(|(_, info)| segments.contains(&info.ino))
+          .map(|(name, _)| name.to_string_lossy())
+          .collect();
+        lines.push(format!("combined={}", names.join(",")));
       },
-      None => {
-        reply.error(ENOENT);
+      Content::Synthetic => lines.push("source=synthetic".into()),
+      _ => {
+        let sources: Vec<_> = info.sources.iter().map(|s| s.path.to_string_lossy()).collect();
+        lines.push(format!("source={}", sources.join(",")));
+        lines.push(format!("offset={}", info.offset));
+        lines.push(format!("range_size={}", info.range_size));
+        lines.push(format!("source_size={}", info.src_size));
       }
     };
+    lines.push(format!("size={}", info.attr.size));
+    lines.push(format!("error={}", info.err));
+    lines.push(format!("preloaded={}", info.preloaded()));
+    lines.push(String::new());
+    lines.join("\n")
   }
 
-  fn getattr(&mut self, _req: &Request, ino: u64, reply: fuser::ReplyAttr) {
+  /// Render resolved mappings in blocks of key=value lines or JSON
+  pub fn render_check(&mut self, json: bool) -> String {
+    let inos: Vec<_> = self.mapped_files().iter().map(|(_, info)| info.ino).collect();
+    // resolve combined files
+    for ino in inos.iter() {
+      self.update(*ino);
+    }
+    if !json {
+      return inos.iter().map(|ino| self.render_description(*ino)).collect::<Vec<_>>().join("\n");
+    }
+    let files: Vec<_> = self.mapped_files().iter().map(|(name, info)| {
+      let sources: Vec<_> = info.sources.iter().map(|s| json_string(&s.path.to_string_lossy())).collect();
+      format!(
+        "{{\"name\":{},\"sources\":[{}],\"offset\":{},\"size\":{},\"range_size\":{},\"source_size\":{},\"error\":{}}}",
+        json_string(&name.to_string_lossy()),
+        sources.join(","),
+        info.offset,
+        info.attr.size,
+        info.range_size,
+        info.src_size,
+        info.err
+      )
+    }).collect();
+    format!("[{}]\n", files.join(","))
+  }
+
+  /// Render source files (one per line)
+  fn render_sources(&self) -> String {
+    let mut sources: Vec<_> = self.inode_map.values()
+      .flat_map(|info| info.sources.iter().map(|s| s.path.to_string_lossy()))
+      .collect();
+    sources.sort();
+    sources.dedup();
+    sources.iter().map(|s| format!("{}\n", s)).collect()
+  }
+
+  /// Render status of the filesystem in JSON
+  fn render_status(&self) -> String {
+    let files = self.mapped_files();
+    let files: Vec<_> = files.iter().map(|(name, info)| {
+      let stats = &info.stats;
+      format!(
+        concat!(
+          "{{\"name\":{},\"ino\":{},\"offset\":{},\"size\":{},\"error\":{},\"version\":{},",
+          "\"reads\":{},\"bytes_read\":{},\"read_errors\":{},\"preloaded\":{},\"cached_bytes\":{},",
+          "\"latency_us\":{{\"p50\":{},\"p90\":{},\"p99\":{}}}}}"
+        ),
+        json_string(&name.to_string_lossy()),
+        info.ino,
+        info.offset,
+        info.attr.size,
+        info.err,
+        info.version,
+        stats.reads.load(Ordering::Relaxed),
+        stats.bytes_read.load(Ordering::Relaxed),
+        stats.errors.load(Ordering::Relaxed),
+        info.preloaded(),
+        info.cached_bytes(),
+        stats.latency.percentile(50),
+        stats.latency.percentile(90),
+        stats.latency.percentile(99)
+      )
+    }).collect();
+    let cache_bytes: u64 = self.inode_map.values().map(|info| info.cached_bytes()).sum();
+    let errored = self.mapped_files().iter().filter(|(_, info)| info.err).count();
+    format!(
+      "{{\"uptime\":{:.3},\"mappings\":{},\"errored\":{},\"cache_bytes\":{},\"files\":[{}]}}\n",
+      self.start_time.elapsed().as_secs_f64(),
+      files.len(),
+      errored,
+      cache_bytes,
+      files.join(",")
+    )
+  }
+
+  /// Read data of the virtual file
+  fn read_inode(&self, info: &InodeInfo, offset: u64, size: u32) -> io::Result<Vec<u8>> {
+    match &info.content {
+      Content::Range | Content::Synthetic => info.read_file(offset, size),
+      Content::Status | Content::Describe(_) | Content::Sources => Ok(slice_at(self.render(info).as_bytes(), offset, size)),
+      // read by read_checksum
+      Content::Checksum(..) => Err(io::Error::from_raw_os_error(EINVAL)),
+      Content::Dir(..) => Err(io::Error::from_raw_os_error(EISDIR)),
+      Content::Combined(segments) => {
+        let size = size as u64;
+        let mut buf = Vec::new();
+        // logical offset of current segment
+        let mut start = 0;
+        for seg in segments {
+          let Some(seg_info) = self.inode_map.get(seg) else { continue };
+          let end = start + seg_info.attr.size;
+          let pos = offset + buf.len() as u64;
+          let remaining = size - buf.len() as u64;
+          if remaining == 0 {
+            break;
+          }
+          if pos < end {
+            let len = cmp::min(end - pos, remaining);
+            let data = seg_info.read_file(pos - start, len as u32)?;
+            let num = data.len() as u64;
+            buf.extend(data);
+            if num < len {
+              // segment shrunk
+              break;
+            }
+          }
+          start = end;
+        }
+        Ok(buf)
+      }
+    }
+  }
+
+  /// Statistics of mapped files (shared with the filesystem) sorted by inode
+  pub fn file_stats(&self) -> FileStats {
+    self.file_stats.clone()
+  }
+
+  /// Entries of directory
+  fn entries(&self, ino: u64) -> Result<&BTreeMap<OsString, u64>, c_int> {
     if ino == FUSE_ROOT_ID {
+      return Ok(&self.file_map);
+    }
+    match self.inode_map.get(&ino).map(|info| &info.content) {
+      Some(Content::Dir(_, entries)) => Ok(entries),
+      Some(_) => Err(ENOTDIR),
+      None => Err(ENOENT)
+    }
+  }
+
+  /// Sorted entries of a directory including "." and ".."
+  fn listing(&self, ino: u64) -> Result<Vec<(u64, FileType, OsString)>, c_int> {
+    let mut entries: Vec<_> = self.entries(ino)?.iter().filter(|(_, ino)| {
+      self.options.show_status || !self.inode_map.get(ino).is_some_and(|info| matches!(info.content, Content::Status))
+    }).map(|(name, ino)| {
+      let kind = self.inode_map.get(ino).map_or(FileType::RegularFile, |info| info.attr.kind);
+      (*ino, kind, name.to_os_string())
+    }).collect();
+    if self.options.order_by == OrderBy::Offset {
+      // ranges first by offset and others after them (stable sort keeps ties by name)
+      entries.sort_by_key(|(ino, _, _)| match self.inode_map.get(ino) {
+        Some(info) if matches!(info.content, Content::Range) => (false, info.offset),
+        _ => (true, 0)
+      });
+    }
+    // root is its own parent
+    let parent = match self.inode_map.get(&ino).map(|info| &info.content) {
+      Some(Content::Dir(parent, _)) => *parent,
+      _ => FUSE_ROOT_ID
+    };
+    let dots = [(".", ino), ("..", parent)].map(|(name, ino)| (ino, FileType::Directory, OsString::from(name)));
+    Ok(dots.into_iter().chain(entries).collect())
+  }
+
+  /// Entries of a directory listing from offset
+  /// (a snapshot is taken at the start of listing so offsets stay valid while it's read in chunks)
+  fn listing_from(&mut self, ino: u64, offset: usize) -> Result<&[(u64, FileType, OsString)], c_int> {
+    if ino == FUSE_ROOT_ID && offset == 0 {
+      self.rescan_dir();
+    }
+    if offset == 0 || !self.listings.contains_key(&ino) {
+      let listing = self.listing(ino)?;
+      self.listings.insert(ino, listing);
+    }
+    let listing = self.listings.get(&ino).map_or(&[][..], |l| l.as_slice());
+    Ok(&listing[cmp::min(offset, listing.len())..])
+  }
+
+  /// Map root to the mapped file when mounted on a file
+  fn resolve(&self, ino: u64) -> u64 {
+    if self.options.file_mount && ino == FUSE_ROOT_ID {
+      // the first (and only) mapped file
+      2
+    } else {
+      ino
+    }
+  }
+
+  /// Poll handles shared with the watcher
+  pub fn pollers(&self) -> Pollers {
+    self.pollers.clone()
+  }
+
+  /// Source files of each mapped file to watch for changes
+  pub fn watch_targets(&self) -> WatchTargets {
+    self.watch_targets.clone()
+  }
+
+  /// Attr with the size of overlay if the file is modified
+  fn overlaid_attr(&self, ino: u64, attr: FileAttr) -> FileAttr {
+    match self.overlays.get(&ino) {
+      Some(overlay) => FileAttr {
+        size: overlay.size,
+        blocks: overlay.size.div_ceil(512),
+        ..attr
+      },
+      None => attr
+    }
+  }
+
+  /// Whether the content can be modified in overlay
+  fn writable(&self, info: &InodeInfo) -> bool {
+    self.options.overlay && matches!(info.content, Content::Range | Content::Synthetic)
+  }
+
+  /// Create an empty file in the root directory (only in overlay)
+  fn create_file(&mut self, name: &OsStr, perm: u16, uid: u32, gid: u32) -> Result<&InodeInfo, c_int> {
+    if !self.options.overlay {
+      return Err(EROFS);
+    }
+    if self.file_map.contains_key(name) {
+      return Err(EEXIST);
+    }
+    let (ino, generation) = self.alloc_ino();
+    // zeros of size 0 as the base of the overlay
+    let config = InodeConfig {
+      name: Some(name.to_string_lossy().into_owned()),
+      synthetic: Some(Synthetic::Zero),
+      size: Some(0),
+      mode: Some(perm),
+      perm_mask: self.options.perm_mask,
+      uid: Some(uid),
+      gid: Some(gid),
+      ..Default::default()
+    };
+    let mut info = InodeInfo::new(ino, config, Arc::new(ScanCache::new(0)));
+    info.generation = generation;
+    self.file_map.insert(name.to_owned(), ino);
+    self.overlays.insert(ino, Overlay::new(0));
+    self.inode_map.insert(ino, info);
+    self.publish();
+    Ok(&self.inode_map[&ino])
+  }
+
+  /// Save content of modified files to the directory
+  fn save_overlays(&self, dir: &Path) -> io::Result<()> {
+    for (name, ino) in self.file_map.iter() {
+      let (Some(overlay), Some(info)) = (self.overlays.get(ino), self.inode_map.get(ino)) else { continue };
+      let mut file = fs::File::create(dir.join(name))?;
+      let mut offset = 0;
+      while offset < overlay.size {
+        let size = cmp::min(overlay.size - offset, READ_CHUNK_SIZE) as u32;
+        let data = overlay.merge(offset, size, self.read_inode(info, offset, size)?);
+        file.write_all(&data)?;
+        offset += size as u64;
+      }
+    }
+    Ok(())
+  }
+
+  /// Total blocks of mapped ranges (and files in overlay) and number of inodes (including root),
+  /// excluding errored inodes whose attr doesn't reflect any data
+  fn usage(&self) -> (u64, u64) {
+    let valid = || self.inode_map.values().filter(|v| !v.err);
+    let blocks = valid()
+      .filter(|v| matches!(v.content, Content::Range) || self.overlays.contains_key(&v.ino))
+      .map(|v| self.overlaid_attr(v.ino, v.attr).blocks)
+      .sum();
+    let root = u64::from(!self.options.file_mount);
+    (blocks, valid().count() as u64 + root)
+  }
+
+  /// Free blocks and inodes: none if read-only,
+  /// otherwise the free memory holding the overlay and unlimited inodes
+  fn available(&self) -> (u64, u64) {
+    if !self.options.overlay {
+      return (0, 0);
+    }
+    let mut info: libc::sysinfo = unsafe { std::mem::zeroed() };
+    let memory = if unsafe { libc::sysinfo(&mut info) } == 0 {
+      (info.freeram as u64).saturating_mul(info.mem_unit as u64)
+    } else {
+      0
+    };
+    (memory / 512, u32::MAX as u64)
+  }
+
+  /// Remove a mapped file from the root directory.
+  /// Its inode is freed once the kernel no longer references it.
+  pub fn remove(&mut self, name: &OsStr) -> bool {
+    let Some(ino) = self.file_map.remove(name) else {
+      return false;
+    };
+    if self.lookups.contains_key(&ino) {
+      self.removed.insert(ino);
+    } else {
+      self.free(ino);
+    }
+    self.publish();
+    true
+  }
+
+  /// Decrease lookup count of an inode and free it if removed and no longer referenced
+  fn release(&mut self, ino: u64, nlookup: u64) {
+    let Some(count) = self.lookups.get_mut(&ino) else {
+      return;
+    };
+    *count = count.saturating_sub(nlookup);
+    if *count == 0 {
+      self.lookups.remove(&ino);
+      if self.removed.remove(&ino) {
+        self.free(ino);
+      }
+    }
+  }
+
+  /// Rescan the source directory (at most once per timeout)
+  /// to add new files and remove the ones gone
+  fn rescan_dir(&mut self) {
+    let Some(dir_source) = &self.options.dir_source else {
+      return;
+    };
+    if self.dir_scanned.elapsed() < self.options.timeout {
+      return;
+    }
+    self.dir_scanned = Instant::now();
+    let configs = match dir_source.configs() {
+      Ok(configs) => configs,
+      Err(err) => {
+        warn!("Error rescanning directory {:?}: {:#}", dir_source.dir, err);
+        return;
+      }
+    };
+    let names: HashSet<OsString> = configs.iter().filter_map(|c| c.name.as_ref().map(OsString::from)).collect();
+    let gone: Vec<_> = self.file_map.iter()
+      .filter(|(name, ino)| !names.contains(*name) && self.inode_map.get(ino).is_some_and(|info| info.config.discovered))
+      .map(|(name, _)| name.clone())
+      .collect();
+    for name in gone {
+      info!("Removing mapped file {:?} gone from source directory", name);
+      self.remove(&name);
+    }
+    let scan_cache = Arc::new(ScanCache::new(self.options.scan_cache_size));
+    let mut added = false;
+    for mut config in configs {
+      let name = OsString::from(config.name.clone().unwrap_or_default());
+      if self.file_map.contains_key(&name) {
+        continue;
+      }
+      if self.options.max_mappings.is_some_and(|max| self.mapped_files().len() >= max) {
+        warn!("Not adding new files in source directory as there are too many mappings (see --max-mappings)");
+        break;
+      }
+      info!("Adding mapped file {:?} new in source directory", name);
+      apply_options(&mut config, &self.options);
+      let (ino, generation) = self.alloc_ino();
+      let mut info = InodeInfo::new(ino, config, scan_cache.clone());
+      if let (Some(limit), false) = (info.config.preload, info.err) {
+        info = preload(info, limit, self.options.preload_timeout, &name);
+      }
+      info.generation = generation;
+      self.inode_map.insert(ino, info);
+      self.file_map.insert(name, ino);
+      added = true;
+    }
+    if added {
+      self.publish();
+    }
+  }
+
+  /// Check whether a read of an inode can be served
+  fn check_read(&self, ino: u64, offset: u64, size: u32) -> Result<&InodeInfo, c_int> {
+    let info = self.inode_map.get(&ino).ok_or(ENOENT)?;
+    if info.err {
+      return Err(EIO);
+    }
+    if self.options.strict_bounds && straddles_end(offset, size, self.overlaid_attr(ino, info.attr).size) {
+      return Err(EINVAL);
+    }
+    Ok(info)
+  }
+
+  /// Read data of an inode (with overlay merged) and record stats,
+  /// refreshing its metadata if the source turns out to be truncated or gone
+  fn read_data(&mut self, ino: u64, offset: u64, size: u32) -> Result<Vec<u8>, c_int> {
+    let info = self.check_read(ino, offset, size)?;
+    let result = self.read_inode(info, offset, size).map(|data| match self.overlays.get(&ino) {
+      Some(overlay) => overlay.merge(offset, size, data),
+      None => data
+    });
+    let (result, truncated, gone) = match result {
+      Ok(data) => {
+        let expected = cmp::min(size as u64, self.overlaid_attr(ino, info.attr).size.saturating_sub(offset));
+        let truncated = matches!(info.content, Content::Range) && (data.len() as u64) < expected;
+        info.stats.record_read(data.len());
+        (Ok(data), truncated, false)
+      },
+      Err(err) => {
+        info.stats.record_error();
+        if err.raw_os_error() == Some(ESTALE) {
+          warn!("Source file of inode {} changed since mount", ino);
+          return Err(ESTALE);
+        }
+        error!("Error reading file of inode {}: {}", ino, err);
+        let gone = matches!(info.content, Content::Range) && err.kind() == io::ErrorKind::NotFound;
+        (Err(EIO), false, gone)
+      }
+    };
+    let track_atime = result.is_ok() && self.options.track_atime;
+    let Some(info) = self.inode_map.get_mut(&ino) else {
+      return result;
+    };
+    if track_atime {
+      info.touch();
+    }
+    if truncated {
+      // refresh now so getattr reports the new size (the short read is returned as is)
+      info.refresh();
+    }
+    if gone {
+      // refresh now instead of after timeout so getattr and lookup agree
+      info!("Source file of inode {} is gone", ino);
+      info.refresh();
+    }
+    result
+  }
+
+  /// Look up a name in a directory and count the reference by kernel
+  /// (returns attr and generation of the entry)
+  fn lookup_entry(&mut self, parent: u64, name: &OsStr) -> Result<(FileAttr, u64), c_int> {
+    if self.options.file_mount {
+      return Err(ENOTDIR);
+    }
+    if parent == FUSE_ROOT_ID && !self.file_map.contains_key(name) {
+      // the file may be new in source directory
+      self.rescan_dir();
+    }
+    let ino = *self.entries(parent)?.get(name).ok_or(ENOENT)?;
+    let Some(info) = self.update(ino) else {
+      error!("Invalid inode {} for file {:?}", ino, name);
+      return Err(EIO);
+    };
+    // same as getattr
+    if info.err {
+      return Err(EIO);
+    }
+    let (attr, generation) = (info.attr, info.generation);
+    *self.lookups.entry(ino).or_default() += 1;
+    Ok((self.overlaid_attr(ino, attr), generation))
+  }
+
+  /// Drop all state of an inode
+  fn free(&mut self, ino: u64) {
+    self.inode_map.remove(&ino);
+    self.overlays.remove(&ino);
+    self.polled_sizes.remove(&ino);
+  }
+
+  /// Whether all readable mapped files are empty (e.g. the source is not written yet)
+  pub fn all_empty(&self) -> bool {
+    let mut readable = self.mapped_files().into_iter()
+      .map(|(_, info)| info)
+      .filter(|info| !info.err)
+      .peekable();
+    readable.peek().is_some() && readable.all(|info| info.attr.size == 0)
+  }
+
+  /// Names of mapped files whose source metadata can't be read
+  pub fn errored_files(&self) -> Vec<&OsStr> {
+    self.file_map.iter()
+      .filter(|(_, ino)| self.inode_map.get(ino).is_some_and(|info| info.err))
+      .map(|(name, _)| name.as_os_str())
+      .collect()
+  }
+}
+
+
+impl Filesystem for RangeFs {
+  fn init(&mut self, _req: &Request, config: &mut KernelConfig) -> Result<(), c_int> {
+    // settings rejected by the kernel (e.g. on older kernels) fall back instead of failing the mount
+    let max_readahead = self.options.max_readahead
+      .and_then(|value| negotiate("max_readahead", value, |v| config.set_max_readahead(v)));
+    let max_background = self.options.max_background
+      .and_then(|value| negotiate("max_background", value, |v| config.set_max_background(v)));
+    let congestion_threshold = self.options.congestion_threshold
+      .and_then(|value| negotiate("congestion_threshold", value, |v| config.set_congestion_threshold(v)));
+    let mut capabilities = Vec::new();
+    if self.options.writeback_cache && enable_capability("writeback_cache", || config.add_capabilities(FUSE_WRITEBACK_CACHE)) {
+      capabilities.push("writeback_cache");
+    }
+    let show = |value: Option<u32>| value.map_or("default".into(), |v| v.to_string());
+    info!(
+      "Kernel config: max_readahead {}, max_background {}, congestion_threshold {}, capabilities [{}]",
+      show(max_readahead),
+      show(max_background.map(|v| background_limit(v, "max_user_bgreq").into())),
+      show(congestion_threshold.map(|v| background_limit(v, "max_user_congthresh").into())),
+      capabilities.join(", ")
+    );
+
+    let mapped: Vec<_> = self.inode_map.values()
+      .filter(|info| matches!(info.content, Content::Range | Content::Synthetic))
+      .collect();
+    let errored = mapped.iter().filter(|info| info.err).count();
+    let bytes: u64 = mapped.iter().map(|info| info.attr.size).sum();
+    info!(
+      "Mounted {:?} at {:?}: {} mappings ({} errored), {} bytes mapped, timeout {:?}, options {:?}",
+      self.file.as_deref().unwrap_or(Path::new("(none)")), self.options.mount_point, mapped.len(), errored, bytes, self.options.timeout, self.options.mount_options
+    );
+    Ok(())
+  }
+
+  fn lookup(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: fuser::ReplyEntry) {
+    self.apply_pending();
+    match self.lookup_entry(parent, name) {
+      Ok((attr, generation)) => reply.entry(&self.options.timeout, &attr, generation),
+      Err(err) => reply.error(err)
+    };
+  }
+
+  // batch_forget falls back to forget by default
+  fn forget(&mut self, _req: &Request<'_>, ino: u64, nlookup: u64) {
+    self.release(ino, nlookup);
+  }
+
+  fn getattr(&mut self, _req: &Request, ino: u64, reply: fuser::ReplyAttr) {
+    self.apply_pending();
+    if ino == FUSE_ROOT_ID && !self.options.file_mount {
       let cur_time = SystemTime::now();
-      reply.attr(&self.timeout, &fuser::FileAttr {
+      let (mtime, ctime) = match (self.options.root_time_from_source, &self.file) {
+        (true, Some(file)) => match fs::metadata(file) {
+          Ok(m) => (
+            m.modified().unwrap_or(cur_time),
+            UNIX_EPOCH + Duration::new(m.ctime().try_into().unwrap_or(0), m.ctime_nsec().try_into().unwrap_or(0))
+          ),
+          Err(err) => {
+            warn!("Error reading source file metadata: {}", err);
+            (cur_time, cur_time)
+          }
+        },
+        _ => (cur_time, cur_time)
+      };
+      reply.attr(&self.options.timeout, &fuser::FileAttr {
         ino: FUSE_ROOT_ID,
         size: 0,
         blocks: 0,
         atime: cur_time,
-        mtime: cur_time,
-        ctime: cur_time,
+        mtime,
+        ctime,
         crtime: cur_time,
         kind: FileType::Directory,
-        perm: 0o777,
-        nlink: 1,
-        uid: 0,
-        gid: 0,
+        // writable by the owner if specified, or by all with overlay so files can be created
+        // (read-only otherwise)
+        perm: match (self.options.uid, self.options.overlay) {
+          (Some(_), _) => 0o755,
+          (None, true) => 0o777,
+          (None, false) => 0o555
+        },
+        nlink: dir_nlink(&self.file_map, &self.inode_map),
+        uid: self.options.uid.unwrap_or(0),
+        gid: self.options.gid.unwrap_or(0),
         rdev: 0,
         blksize: 512,
         flags: 0
       });
     } else {
-      if let Some(info) = self.inode_map.get_mut(&ino) {
-        info.update_info(&self.file, self.timeout);
+      let timeout = self.options.timeout;
+      if let Some(info) = self.update(self.resolve(ino)) {
         if info.err {
           reply.error(EIO);
           return;
         }
-        reply.attr(&self.timeout, &info.attr);
+        let attr = FileAttr { ino, ..info.attr };
+        reply.attr(&timeout, &self.overlaid_attr(self.resolve(ino), attr));
       } else {
         reply.error(ENOENT);
       }
@@ -157,19 +1511,25 @@ impl Filesystem for RangeFs {
     offset: i64,
     mut reply: ReplyDirectory,
   ) {
-    if ino != FUSE_ROOT_ID {
-      reply.error(ENOENT);
+    self.apply_pending();
+    if self.options.file_mount {
+      reply.error(ENOTDIR);
       return;
     }
-    assert!(offset >= 0);
-
-    let entries = self.file_map.iter().map(|(name, ino)| {
-      (ino.clone(), FileType::RegularFile, name.to_os_string())
-    });
-
-    for (i, e) in entries.enumerate().skip(offset as usize) {
+    if offset < 0 {
+      reply.error(EINVAL);
+      return;
+    }
+    let listing = match self.listing_from(ino, offset as usize) {
+      Ok(listing) => listing,
+      Err(err) => {
+        reply.error(err);
+        return;
+      }
+    };
+    for (i, e) in listing.iter().enumerate() {
       // offset is used by kernel for future readdir calls (should be next entry)
-      if reply.add(e.0, (i+1) as i64, e.1, &e.2) {
+      if reply.add(e.0, offset + i as i64 + 1, e.1, &e.2) {
         // return true when buffer full
         break;
       }
@@ -178,16 +1538,27 @@ impl Filesystem for RangeFs {
     reply.ok();
   }
 
-  fn open(&mut self, _req: &Request, ino: u64, _flags: i32, reply: fuser::ReplyOpen) {
-    match self.inode_map.get_mut(&ino) {
+  fn open(&mut self, _req: &Request, ino: u64, flags: i32, reply: fuser::ReplyOpen) {
+    self.apply_pending();
+    let ino = self.resolve(ino);
+    let overlay = self.options.overlay;
+    // reads from page cache are page-aligned so bounds can only be checked with direct I/O
+    let direct_io = self.options.direct_io || self.options.strict_bounds;
+    match self.update(ino) {
       Some(info) => {
-        info.update_info(&self.file, self.timeout);
         if info.err {
           reply.error(EIO);
           return;
         }
+        // only mapped files can be modified in overlay
+        if flags & O_ACCMODE != O_RDONLY && !(overlay && matches!(info.content, Content::Range | Content::Synthetic)) {
+          reply.error(EROFS);
+          return;
+        }
         // Return dummy fh and flags as we only use ino in read
-        reply.opened(0, 0);
+        // (internal files are always rendered freshly without page cache)
+        let flags = if direct_io || info.content.rendered() { FOPEN_DIRECT_IO } else { 0 };
+        reply.opened(0, flags);
       },
       None => reply.error(ENOENT)
     };
@@ -204,41 +1575,847 @@ impl Filesystem for RangeFs {
     _lock_owner: Option<u64>,
     reply: fuser::ReplyData,
   ) {
-    assert!(offset >= 0);
+    self.apply_pending();
+    let ino = self.resolve(ino);
+    if offset < 0 {
+      reply.error(EINVAL);
+      return;
+    }
+    let info = match self.check_read(ino, offset as u64, size) {
+      Ok(info) => info,
+      Err(err) => {
+        reply.error(err);
+        return;
+      }
+    };
+    if let Content::Checksum(target, checksum) = info.content {
+      self.read_checksum(target, checksum, info.stats.clone(), offset as u64, size, reply);
+      return;
+    }
+    match self.read_data(ino, offset as u64, size) {
+      Ok(data) => reply.data(&data),
+      Err(err) => reply.error(err)
+    };
+  }
+
+  fn write(
+    &mut self,
+    _req: &Request,
+    ino: u64,
+    _fh: u64,
+    offset: i64,
+    data: &[u8],
+    _write_flags: u32,
+    _flags: i32,
+    _lock_owner: Option<u64>,
+    reply: fuser::ReplyWrite,
+  ) {
+    self.apply_pending();
+    let ino = self.resolve(ino);
+    if offset < 0 {
+      reply.error(EINVAL);
+      return;
+    }
+    let Some(info) = self.inode_map.get(&ino) else {
+      reply.error(ENOENT);
+      return;
+    };
+    if !self.writable(info) {
+      reply.error(EROFS);
+      return;
+    }
+    if info.err {
+      reply.error(EIO);
+      return;
+    }
+    let mut overlay = self.overlays.remove(&ino).unwrap_or_else(|| Overlay::new(info.attr.size));
+    let result = overlay.write(offset as u64, data, |o, s| self.read_inode(info, o, s));
+    self.overlays.insert(ino, overlay);
+    match result {
+      Ok(()) => reply.written(data.len() as u32),
+      Err(err) => {
+        error!("Error writing file of inode {}: {}", ino, err);
+        reply.error(EIO);
+      }
+    };
+  }
+
+  fn setattr(
+    &mut self,
+    _req: &Request,
+    ino: u64,
+    _mode: Option<u32>,
+    _uid: Option<u32>,
+    _gid: Option<u32>,
+    size: Option<u64>,
+    _atime: Option<TimeOrNow>,
+    _mtime: Option<TimeOrNow>,
+    _ctime: Option<SystemTime>,
+    _fh: Option<u64>,
+    _crtime: Option<SystemTime>,
+    _chgtime: Option<SystemTime>,
+    _bkuptime: Option<SystemTime>,
+    _flags: Option<u32>,
+    reply: fuser::ReplyAttr,
+  ) {
+    self.apply_pending();
+    let resolved = self.resolve(ino);
+    let timeout = self.options.timeout;
+    let Some(info) = self.update(resolved) else {
+      reply.error(ENOENT);
+      return;
+    };
+    if info.err {
+      reply.error(EIO);
+      return;
+    }
+    let attr = FileAttr { ino, ..info.attr };
+    // only truncation is supported (other attributes are ignored)
+    if let Some(size) = size {
+      if !self.inode_map.get(&resolved).is_some_and(|info| self.writable(info)) {
+        reply.error(EROFS);
+        return;
+      }
+      self.overlays.entry(resolved).or_insert_with(|| Overlay::new(attr.size)).truncate(size);
+    }
+    reply.attr(&timeout, &self.overlaid_attr(resolved, attr));
+  }
+
+  fn create(
+    &mut self,
+    req: &Request<'_>,
+    parent: u64,
+    name: &OsStr,
+    mode: u32,
+    umask: u32,
+    _flags: i32,
+    reply: fuser::ReplyCreate,
+  ) {
+    self.apply_pending();
+    // only files in the root directory can be created
+    if parent != FUSE_ROOT_ID || self.options.file_mount {
+      reply.error(EROFS);
+      return;
+    }
+    let timeout = self.options.timeout;
+    let flags = if self.options.direct_io { FOPEN_DIRECT_IO } else { 0 };
+    match self.create_file(name, created_perm(mode, umask), req.uid(), req.gid()) {
+      Ok(info) => {
+        let (ino, attr, generation) = (info.ino, info.attr, info.generation);
+        *self.lookups.entry(ino).or_default() += 1;
+        reply.created(&timeout, &attr, generation, 0, flags);
+      },
+      Err(err) => reply.error(err)
+    };
+  }
+
+  fn destroy(&mut self) {
+    if let Some(dir) = &self.options.overlay_save {
+      if let Err(err) = self.save_overlays(dir) {
+        error!("Error saving modified files to {:?}: {}", dir, err);
+      }
+    }
+  }
+
+  fn poll(
+    &mut self,
+    _req: &Request<'_>,
+    ino: u64,
+    _fh: u64,
+    kh: u64,
+    _events: u32,
+    flags: u32,
+    reply: fuser::ReplyPoll
+  ) {
+    self.apply_pending();
+    let ino = self.resolve(ino);
+    // always check the latest size
+    let size = match self.update_with_timeout(ino, Duration::ZERO) {
+      Some(info) => info.attr.size,
+      None => {
+        reply.error(ENOENT);
+        return;
+      }
+    };
+    // readable if it's never polled or has grown since last poll
+    if self.polled_sizes.insert(ino, size).is_none_or(|s| s != size) {
+      reply.poll((POLLIN | POLLRDNORM) as u32);
+      return;
+    }
+    if flags & FUSE_POLL_SCHEDULE_NOTIFY != 0 {
+      self.pollers.lock().unwrap_or_else(PoisonError::into_inner).entry(ino).or_default().push(kh);
+    }
+    reply.poll(0);
+  }
+
+  fn getxattr(&mut self, _req: &Request<'_>, ino: u64, name: &OsStr, size: u32, reply: ReplyXattr) {
+    self.apply_pending();
+    let ino = self.resolve(ino);
+    if name.to_str() == Some(XATTR_VERSION) {
+      // detect changes of source if outdated
+      self.update(ino);
+    }
     match self.inode_map.get(&ino) {
       Some(info) => {
-        if info.err {
-          reply.error(EIO);
-          return;
-        }
-        let o = info.config.offset.unwrap_or(0) + offset as u64;
-        let s = cmp::min(info.attr.size.saturating_sub(offset as u64), size as u64);
-        match read_at(&self.file, o, s as usize) {
-          Ok(data) => {
-            reply.data(&data);
-          },
-          Err(err) => {
-            error!("Error reading file {:?}: {}", self.file, err);
-            reply.error(EIO);
+        let value = match name.to_str() {
+          Some(XATTR_LATENCY) => info.stats.latency.summary(),
+          Some(XATTR_PRELOADED) => info.preloaded().to_string(),
+          Some(XATTR_CACHED_BYTES) => info.cached_bytes().to_string(),
+          Some(XATTR_VERSION) => info.version.to_string(),
+          _ => {
+            reply.error(ENODATA);
+            return;
           }
-        }
+        };
+        reply_xattr(size, value.as_bytes(), reply);
       },
       None => reply.error(ENOENT)
     };
   }
 
+  fn listxattr(&mut self, _req: &Request<'_>, ino: u64, size: u32, reply: ReplyXattr) {
+    self.apply_pending();
+    let ino = self.resolve(ino);
+    if self.inode_map.contains_key(&ino) {
+      // null-terminated names
+      let names = format!("{}\0{}\0{}\0{}\0", XATTR_LATENCY, XATTR_PRELOADED, XATTR_CACHED_BYTES, XATTR_VERSION);
+      reply_xattr(size, names.as_bytes(), reply);
+    } else {
+      reply.error(ENOENT);
+    }
+  }
+
   fn statfs(&mut self, _req: &Request<'_>, _ino: u64, reply: fuser::ReplyStatfs) {
-    // Sum up all the blocks
-    let blocks: u64 = self.inode_map.values().map(|v| v.attr.blocks).sum();
-    // convert to c-style string without encoding/decoding
-    reply.statfs(blocks, 0, 0, self.inode_map.len() as u64, 0, 512, 255, 512);
+    self.apply_pending();
+    let (blocks, files) = self.usage();
+    // read-only (flag set by mount options) unless writes go to the overlay
+    let (free, ffree) = self.available();
+    reply.statfs(blocks + free, free, free, files + ffree, ffree, 512, 255, 512);
   }
 }
 
-fn read_at(path: impl AsRef<Path>, offset: u64, size: usize) -> io::Result<Vec<u8>> {
-  let f = fs::File::open(path)?;
-  let mut buf = vec![0; size as usize];
-  let num = f.read_at(&mut buf, offset)?;
-  buf.resize(num, 0);
-  Ok(buf)
+/// Reply size of xattr data if size is 0, otherwise the data
+fn reply_xattr(size: u32, data: &[u8], reply: ReplyXattr) {
+  if size == 0 {
+    reply.size(data.len() as u32);
+  } else if data.len() > size as usize {
+    reply.error(ERANGE);
+  } else {
+    reply.data(data);
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::os::unix::fs::PermissionsExt;
+  use crate::{config::{DuplicateKey, parse_config}, metadata::Source, transform};
+
+  /// Write a source file unique to the test
+  fn source(name: &str, data: &[u8]) -> PathBuf {
+    let path = std::env::temp_dir().join(format!("rangefs-{}-{}", std::process::id(), name));
+    fs::write(&path, data).unwrap();
+    path
+  }
+
+  fn configs(configs: &[&str]) -> Vec<InodeConfig> {
+    configs.iter().map(|c| parse_config(c, DuplicateKey::Error).unwrap()).collect()
+  }
+
+  fn ino(fs: &RangeFs, name: &str) -> u64 {
+    fs.file_map[OsStr::new(name)]
+  }
+
+  #[test]
+  fn zero_timeout_refreshes_every_time() {
+    let src = source("zero-timeout", b"0123456789");
+    let options = FsOptions { timeout: Duration::ZERO, ..Default::default() };
+    let mut fs = RangeFs::new(Some(src.clone()), configs(&["name=a"]), options).unwrap();
+    let a = ino(&fs, "a");
+    for size in [10, 4, 7] {
+      fs::write(&src, &b"0123456789"[..size]).unwrap();
+      assert_eq!(fs.update(a).unwrap().attr.size, size as u64);
+    }
+
+    // cached until the timeout expires otherwise
+    let options = FsOptions { timeout: Duration::from_secs(3600), ..Default::default() };
+    let mut fs = RangeFs::new(Some(src.clone()), configs(&["name=a"]), options).unwrap();
+    fs::write(&src, b"01").unwrap();
+    assert_eq!(fs.update(a).unwrap().attr.size, 7);
+    fs::remove_file(src).unwrap();
+  }
+
+  #[test]
+  fn replaced_source_is_reopened() {
+    let src = source("replaced", b"old content");
+    let options = FsOptions { timeout: Duration::ZERO, ..Default::default() };
+    let mut fs = RangeFs::new(Some(src.clone()), configs(&["name=a"]), options).unwrap();
+    let a = ino(&fs, "a");
+    assert_eq!(fs.read_inode(&fs.inode_map[&a], 0, 100).unwrap(), b"old content");
+
+    // atomic replacement by rename (like log rotation)
+    let new = source("replaced.new", b"new");
+    fs::rename(new, &src).unwrap();
+    fs.update(a);
+    assert_eq!(fs.read_inode(&fs.inode_map[&a], 0, 100).unwrap(), b"new");
+    fs::remove_file(src).unwrap();
+  }
+
+  #[test]
+  fn source_without_file_name() {
+    for path in ["/", "/tmp/.."] {
+      let config = format!("source={}:size=1", path);
+      let err = RangeFs::new(None, configs(&[&config]), FsOptions::default()).err().unwrap();
+      assert_eq!(err.to_string(), format!("invalid source file (no file name to use as default name): {:?}", path));
+    }
+    // fine with an explicit name
+    assert!(RangeFs::new(None, configs(&["name=a:source=/tmp/..:size=1"]), FsOptions::default()).is_ok());
+  }
+
+  #[test]
+  fn offset_near_max() {
+    let src = source("offset-max", b"0123456789");
+    let compute = source("offset-max.sh", format!("#!/bin/sh\necho {} 2\n", u64::MAX - 1).as_bytes());
+    fs::set_permissions(&compute, fs::Permissions::from_mode(0o755)).unwrap();
+    let configs = configs(&[
+      &format!("name=a:offset={}", u64::MAX),
+      &format!("name=b:offset={}:pad=true", u64::MAX - 1),
+      &format!("name=c:compute={}", compute.display())
+    ]);
+    let fs = RangeFs::new(Some(src.clone()), configs, FsOptions::default()).unwrap();
+    for name in ["a", "b"] {
+      let info = &fs.inode_map[&ino(&fs, name)];
+      assert_eq!(info.attr.size, 0);
+      assert_eq!(fs.read_inode(info, 0, 10).unwrap(), b"");
+      assert_eq!(fs.read_inode(info, u64::MAX, 10).unwrap(), b"");
+    }
+    // overflowing range resolved at runtime
+    assert!(fs.inode_map[&ino(&fs, "c")].err);
+    fs::remove_file(src).unwrap();
+    fs::remove_file(compute).unwrap();
+  }
+
+  #[test]
+  fn xor_adjacent_reads() {
+    let data: Vec<u8> = (0..=255).collect();
+    let src = source("xor", &data);
+    let fs = RangeFs::new(Some(src.clone()), configs(&["name=a:offset=3:xor=0102a5"]), FsOptions::default()).unwrap();
+    let info = &fs.inode_map[&ino(&fs, "a")];
+    let whole = fs.read_inode(info, 0, 1000).unwrap();
+    // key aligned to the start of the range
+    let key = [0x01, 0x02, 0xa5];
+    let expected: Vec<u8> = data[3..].iter().enumerate().map(|(i, b)| b ^ key[i % 3]).collect();
+    assert_eq!(whole, expected);
+    for split in [1, 2, 3, 4, 100, 252] {
+      let mut parts = fs.read_inode(info, 0, split).unwrap();
+      parts.extend(fs.read_inode(info, split as u64, 1000).unwrap());
+      assert_eq!(parts, whole, "split at {}", split);
+    }
+    fs::remove_file(src).unwrap();
+  }
+
+  #[test]
+  fn lookup_errored_source() {
+    let src = source("lookup-errored", b"0123456789");
+    let missing = src.with_extension("missing");
+    let configs = configs(&["name=a:size=4", &format!("name=b:source={}", missing.display())]);
+    let mut fs = RangeFs::new(Some(src.clone()), configs, FsOptions::default()).unwrap();
+    let (attr, _) = fs.lookup_entry(FUSE_ROOT_ID, OsStr::new("a")).unwrap();
+    assert_eq!(attr.size, 4);
+    assert_eq!(fs.lookup_entry(FUSE_ROOT_ID, OsStr::new("b")), Err(EIO));
+    assert_eq!(fs.lookup_entry(FUSE_ROOT_ID, OsStr::new("c")), Err(ENOENT));
+    assert_eq!(fs.lookup_entry(ino(&fs, "a"), OsStr::new("b")), Err(ENOTDIR));
+    // only successful lookups are referenced by kernel
+    assert_eq!(fs.lookups.get(&ino(&fs, "a")), Some(&1));
+    assert!(!fs.lookups.contains_key(&ino(&fs, "b")));
+    fs::remove_file(src).unwrap();
+  }
+
+  #[test]
+  fn listing_starts_with_dots() {
+    let src = source("dots", b"0123456789");
+    let options = FsOptions { introspect: true, ..Default::default() };
+    let fs = RangeFs::new(Some(src.clone()), configs(&["name=b", "name=a"]), options).unwrap();
+    let names = |ino| fs.listing(ino).unwrap().into_iter().map(|(ino, _, name)| (ino, name)).collect::<Vec<_>>();
+    let root = names(FUSE_ROOT_ID);
+    assert_eq!(root[..2], [(FUSE_ROOT_ID, ".".into()), (FUSE_ROOT_ID, "..".into())]);
+    assert_eq!(root[2..].iter().map(|(_, name)| name.to_str().unwrap()).collect::<Vec<_>>(), [".rangefs", "a", "b"]);
+    // subdirectories refer to their parents
+    let dir = ino(&fs, ".rangefs");
+    assert_eq!(names(dir)[..2], [(dir, ".".into()), (FUSE_ROOT_ID, "..".into())]);
+    let mappings = fs.entries(dir).unwrap()[OsStr::new("mappings")];
+    assert_eq!(names(mappings)[..2], [(mappings, ".".into()), (dir, "..".into())]);
+    assert!(fs.listing(FUSE_ROOT_ID).unwrap()[..2].iter().all(|(_, kind, _)| *kind == FileType::Directory));
+    assert_eq!(fs.listing(ino(&fs, "a")), Err(ENOTDIR));
+    fs::remove_file(src).unwrap();
+  }
+
+  #[test]
+  fn reads_at_eof() {
+    let src = source("eof", b"0123456789");
+    let configs = configs(&["name=a:offset=2:size=3", "name=p:preload", "name=z:source=zero:size=4", "name=h:size=3:transform=hex"]);
+    let fs = RangeFs::new(Some(src.clone()), configs, FsOptions::default()).unwrap();
+    for (name, size) in [("a", 3), ("p", 10), ("z", 4), ("h", 6)] {
+      let info = &fs.inode_map[&ino(&fs, name)];
+      assert_eq!(info.attr.size, size);
+      for offset in [size, size + 1, size + 100, u64::MAX] {
+        assert_eq!(fs.read_inode(info, offset, 10).unwrap(), b"", "{} at {}", name, offset);
+      }
+    }
+    // not touching the source at EOF
+    fs::remove_file(src).unwrap();
+    assert_eq!(fs.read_inode(&fs.inode_map[&ino(&fs, "a")], 3, 10).unwrap(), b"");
+  }
+
+  #[test]
+  fn errored_inode_owner() {
+    let missing = std::env::temp_dir().join(format!("rangefs-{}-errored-owner", std::process::id()));
+    let fs = RangeFs::new(Some(missing), configs(&["name=a:uid=1000:gid=100:mode=640", "name=b"]), FsOptions::default()).unwrap();
+    let info = &fs.inode_map[&ino(&fs, "a")];
+    assert!(info.err);
+    assert_eq!((info.attr.uid, info.attr.gid, info.attr.perm), (1000, 100, 0o640));
+    let info = &fs.inode_map[&ino(&fs, "b")];
+    assert!(info.err);
+    assert_eq!((info.attr.uid, info.attr.gid, info.attr.perm), (0, 0, 0o666));
+    // options of the filesystem apply as well
+    let options = FsOptions { uid: Some(1000), gid: Some(1000), ..Default::default() };
+    let fs = RangeFs::new(Some("/nonexistent".into()), configs(&["name=b"]), options).unwrap();
+    let info = &fs.inode_map[&ino(&fs, "b")];
+    assert_eq!((info.attr.uid, info.attr.gid), (1000, 1000));
+  }
+
+  #[test]
+  fn source_gone_consistently() {
+    let src = source("gone", b"0123456789");
+    let options = FsOptions { timeout: Duration::from_secs(3600), ..Default::default() };
+    let mut fs = RangeFs::new(Some(src.clone()), configs(&["name=a:offset=2:size=3"]), options).unwrap();
+    let a = ino(&fs, "a");
+    assert_eq!(fs.read_data(a, 0, 10), Ok(b"234".to_vec()));
+    // without an opened file kept
+    for source in fs.inode_map.get_mut(&a).unwrap().sources.iter_mut() {
+      *source = Source::new(src.clone(), false);
+    }
+    fs::remove_file(&src).unwrap();
+    assert_eq!(fs.read_data(a, 0, 10), Err(EIO));
+    // getattr and lookup agree right away instead of after the timeout
+    assert!(fs.update(a).unwrap().err);
+    assert_eq!(fs.lookup_entry(FUSE_ROOT_ID, OsStr::new("a")), Err(EIO));
+    assert_eq!(fs.read_data(a, 0, 10), Err(EIO));
+    assert_eq!(fs.inode_map[&a].stats.errors.load(Ordering::Relaxed), 1);
+  }
+
+  #[test]
+  fn direct_io_sees_rewrites() {
+    let src = source("direct-io", b"0123456789");
+    let options = FsOptions { direct_io: true, timeout: Duration::ZERO, ..Default::default() };
+    let mut fs = RangeFs::new(Some(src.clone()), configs(&["name=a:offset=2"]), options).unwrap();
+    let a = ino(&fs, "a");
+    assert_eq!(fs.read_data(a, 0, 100), Ok(b"23456789".to_vec()));
+    // rewritten in place by another process
+    fs::write(&src, b"abcdefghij").unwrap();
+    assert_eq!(fs.read_data(a, 0, 100), Ok(b"cdefghij".to_vec()));
+    fs::write(&src, b"ABCDEFGHIJKL").unwrap();
+    assert_eq!(fs.update(a).unwrap().attr.size, 10);
+    assert_eq!(fs.read_data(a, 0, 100), Ok(b"CDEFGHIJKL".to_vec()));
+    fs::remove_file(src).unwrap();
+  }
+
+  #[test]
+  fn perm_mask() {
+    let src = source("perm-mask", b"0123456789");
+    fs::set_permissions(&src, fs::Permissions::from_mode(0o777)).unwrap();
+    let options = FsOptions { perm_mask: 0o022, ..Default::default() };
+    let fs = RangeFs::new(Some(src.clone()), configs(&["name=a", "name=b:mode=666"]), options).unwrap();
+    assert_eq!(fs.inode_map[&ino(&fs, "a")].attr.perm, 0o755);
+    // applied after mode
+    assert_eq!(fs.inode_map[&ino(&fs, "b")].attr.perm, 0o644);
+    let fs = RangeFs::new(Some(src.clone()), configs(&["name=a"]), FsOptions::default()).unwrap();
+    assert_eq!(fs.inode_map[&ino(&fs, "a")].attr.perm, 0o777);
+    fs::remove_file(src).unwrap();
+  }
+
+  #[test]
+  fn dir_link_counts() {
+    let src = source("nlink", b"0123456789");
+    let fs = RangeFs::new(Some(src.clone()), configs(&["name=a", "name=b"]), FsOptions::default()).unwrap();
+    assert_eq!(dir_nlink(&fs.file_map, &fs.inode_map), 2);
+    // nested: .rangefs with mappings inside
+    let options = FsOptions { introspect: true, ..Default::default() };
+    let fs = RangeFs::new(Some(src.clone()), configs(&["name=a", "name=b"]), options).unwrap();
+    assert_eq!(dir_nlink(&fs.file_map, &fs.inode_map), 3);
+    let dir = &fs.inode_map[&ino(&fs, ".rangefs")];
+    assert_eq!(dir.attr.nlink, 3);
+    assert_eq!(fs.inode_map[&fs.entries(dir.ino).unwrap()[OsStr::new("mappings")]].attr.nlink, 2);
+    fs::remove_file(src).unwrap();
+  }
+
+  #[test]
+  fn refresh_preload() {
+    for refresh in [false, true] {
+      let src = source(&format!("refresh-preload-{}", refresh), b"0123456789");
+      let options = FsOptions { refresh_preload: refresh, ..Default::default() };
+      let mut fs = RangeFs::new(Some(src.clone()), configs(&["name=a:preload"]), options).unwrap();
+      let a = ino(&fs, "a");
+      assert!(fs.inode_map[&a].preloaded());
+      fs::write(&src, b"abcdefghijklmn").unwrap();
+      // as if the timeout expired
+      fs.update_with_timeout(a, Duration::ZERO);
+      let info = &fs.inode_map[&a];
+      let data = fs.read_inode(info, 0, 100).unwrap();
+      if refresh {
+        assert_eq!(data, b"abcdefghijklmn");
+        assert_eq!(info.cached_bytes(), 14);
+      } else {
+        assert_eq!(data, b"0123456789");
+        assert_eq!(info.attr.size, 10);
+      }
+      assert!(info.preloaded());
+      fs::remove_file(src).unwrap();
+    }
+  }
+
+  /// Number of read syscalls by the current thread
+  fn read_syscalls() -> u64 {
+    let io = fs::read_to_string("/proc/thread-self/io").unwrap();
+    io.lines().find_map(|l| l.strip_prefix("syscr: ")).unwrap().parse().unwrap()
+  }
+
+  #[test]
+  fn chunked_source_reads() {
+    let data: Vec<u8> = (0..(10 << 20) + 123).map(|i| (i % 253) as u8).collect();
+    let src = source("read-chunk", &data);
+    for chunk in [None, Some(1 << 20), Some(1000)] {
+      let options = FsOptions { source_read_chunk: chunk, ..Default::default() };
+      let fs = RangeFs::new(Some(src.clone()), configs(&["name=a:offset=7"]), options).unwrap();
+      let info = &fs.inode_map[&ino(&fs, "a")];
+      let before = read_syscalls();
+      let read = fs.read_inode(info, 0, 10 << 20).unwrap();
+      // including a few reads of the counter itself
+      let reads = read_syscalls() - before;
+      assert_eq!(read, data[7..7 + (10 << 20)]);
+      match chunk {
+        None => assert!(reads < 10, "{} reads", reads),
+        Some(chunk) => assert!(reads >= (10 << 20) / chunk, "{} reads of chunk {}", reads, chunk)
+      };
+      // reading the rest
+      assert_eq!(fs.read_inode(info, 10 << 20, 1000).unwrap(), data[7 + (10 << 20)..]);
+    }
+    fs::remove_file(src).unwrap();
+  }
+
+  #[test]
+  fn lookup_forget_cycles() {
+    let src = source("forget", b"0123456789");
+    let mut fs = RangeFs::new(Some(src.clone()), configs(&["name=a:size=3", "name=b:offset=3"]), FsOptions::default()).unwrap();
+    let (a, b) = (ino(&fs, "a"), ino(&fs, "b"));
+    for _ in 0..100 {
+      fs.lookup_entry(FUSE_ROOT_ID, OsStr::new("a")).unwrap();
+      fs.release(a, 1);
+    }
+    // static mappings are kept
+    assert!(fs.lookups.is_empty());
+    assert!(fs.inode_map.contains_key(&a));
+
+    // removed mapping is freed once no longer referenced
+    fs.lookup_entry(FUSE_ROOT_ID, OsStr::new("a")).unwrap();
+    fs.lookup_entry(FUSE_ROOT_ID, OsStr::new("a")).unwrap();
+    assert!(fs.remove(OsStr::new("a")));
+    assert_eq!(fs.lookup_entry(FUSE_ROOT_ID, OsStr::new("a")), Err(ENOENT));
+    fs.release(a, 1);
+    assert!(fs.inode_map.contains_key(&a));
+    fs.release(a, 1);
+    assert!(!fs.inode_map.contains_key(&a));
+    assert!(fs.removed.is_empty());
+    // forgetting more than looked up or unknown inodes
+    fs.release(a, 5);
+    fs.release(12345, 1);
+    assert!(fs.remove(OsStr::new("b")));
+    assert!(!fs.inode_map.contains_key(&b));
+    assert!(!fs.remove(OsStr::new("b")));
+    fs::remove_file(src).unwrap();
+  }
+
+  #[test]
+  fn usage_excludes_errored() {
+    let src = source("statfs", &[0; 2048]);
+    let missing = src.with_extension("missing");
+    let b = format!("name=b:source={}:size=4096", missing.display());
+    let fs = RangeFs::new(Some(src.clone()), configs(&["name=a:size=1024", &b, "name=c:offset=1024"]), FsOptions::default()).unwrap();
+    // blocks of a and c, and inodes of a, c, the status file and root
+    assert_eq!(fs.usage(), (4, 4));
+    assert!(fs.render_status().contains("\"errored\":1"));
+    let fs = RangeFs::new(Some(missing), configs(&["name=a", "name=b"]), FsOptions::default()).unwrap();
+    assert_eq!(fs.usage(), (0, 2));
+    fs::remove_file(src).unwrap();
+  }
+
+  #[test]
+  fn size_clamped_unless_padded() {
+    let src = source("pad", b"0123456789");
+    let configs = configs(&["name=a:offset=6:size=8", "name=b:offset=6:size=8:pad=true", "name=c:offset=2:size=3", "name=d:offset=20:size=4"]);
+    let mut fs = RangeFs::new(Some(src.clone()), configs, FsOptions::default()).unwrap();
+    let cases: [(&str, u64, &[u8]); 4] = [("a", 4, b"6789"), ("b", 8, b"6789\0\0\0\0"), ("c", 3, b"234"), ("d", 0, b"")];
+    for (name, size, data) in cases {
+      let ino = ino(&fs, name);
+      assert_eq!(fs.update(ino).unwrap().attr.size, size, "{}", name);
+      let info = &fs.inode_map[&ino];
+      // attr and data agree
+      assert_eq!(fs.read_inode(info, 0, 100).unwrap(), data, "{}", name);
+      assert_eq!(fs.read_inode(info, 2, 100).unwrap(), data[cmp::min(2, data.len())..], "{}", name);
+    }
+    fs::remove_file(src).unwrap();
+  }
+
+  #[test]
+  fn transformed_reads_at_offsets() {
+    let data: Vec<u8> = (0..=255).cycle().take(1000).collect();
+    let src = source("transforms", &data);
+    let configs = configs(&[
+      "name=h:offset=7:size=301:transform=hex",
+      "name=b:offset=3:size=500:transform=base64",
+      "name=hb:offset=5:size=333:transform=hex,base64",
+      "name=x:offset=1:size=100:transform=base64,hex:xor=a5",
+      "name=p:offset=2:size=50:transform=hex:preload"
+    ]);
+    let fs = RangeFs::new(Some(src.clone()), configs, FsOptions::default()).unwrap();
+    for (name, offset, size, key) in [("h", 7, 301, 0), ("b", 3, 500, 0), ("hb", 5, 333, 0), ("x", 1, 100, 0xa5), ("p", 2, 50, 0)] {
+      let info = &fs.inode_map[&ino(&fs, name)];
+      let raw = data[offset..offset + size].iter().map(|b| b ^ key).collect();
+      let whole = transform::apply_all(&info.config.transforms, raw).unwrap();
+      assert_eq!(info.attr.size, whole.len() as u64);
+      let len = whole.len() as u64;
+      for offset in [0, 1, 2, 3, 5, 7, 100, 333, len - 3, len, len + 10] {
+        for size in [1, 2, 3, 4, 5, 17, 4096] {
+          let start = cmp::min(offset, len) as usize;
+          let end = cmp::min(start + size as usize, whole.len());
+          assert_eq!(fs.read_inode(info, offset, size).unwrap(), whole[start..end], "{} at {} of {}", name, offset, size);
+        }
+      }
+      // seekable transforms only read the window needed
+      assert_eq!(info.preloaded(), name == "p");
+    }
+    fs::remove_file(src).unwrap();
+  }
+
+  #[test]
+  fn listing_in_chunks() {
+    let names: Vec<_> = (0..3000).map(|i| format!("f{:04}", (i * 7919) % 3000)).collect();
+    let strs: Vec<_> = names.iter().map(|name| format!("name={}:source=zero:size=1", name)).collect();
+    let strs: Vec<_> = strs.iter().map(String::as_str).collect();
+    let mut fs = RangeFs::new(None, configs(&strs), FsOptions::default()).unwrap();
+    // read in chunks as the kernel does when its buffer is full
+    let mut listed = Vec::new();
+    loop {
+      let chunk: Vec<_> = fs.listing_from(FUSE_ROOT_ID, listed.len()).unwrap().iter().take(100).cloned().collect();
+      if chunk.is_empty() {
+        break;
+      }
+      if listed.len() == 1000 {
+        // changes during listing don't shift offsets
+        assert!(fs.remove(OsStr::new("f0001")));
+      }
+      listed.extend(chunk.into_iter().map(|(_, _, name)| name.into_string().unwrap()));
+    }
+    let mut expected = names.clone();
+    expected.sort();
+    assert_eq!(listed[..2], [".", ".."]);
+    assert_eq!(listed[2..], expected);
+    // new listing from the start
+    assert_eq!(fs.listing_from(FUSE_ROOT_ID, 0).unwrap().len(), 3001);
+    assert!(fs.listing_from(FUSE_ROOT_ID, 5000).unwrap().is_empty());
+  }
+
+  #[test]
+  fn reads_straddling_end() {
+    let src = source("strict-bounds", b"0123456789");
+    for strict_bounds in [false, true] {
+      let options = FsOptions { strict_bounds, ..Default::default() };
+      let mut fs = RangeFs::new(Some(src.clone()), configs(&["name=a:offset=2:size=6"]), options).unwrap();
+      let a = ino(&fs, "a");
+      assert_eq!(fs.read_data(a, 0, 6), Ok(b"234567".to_vec()));
+      assert_eq!(fs.read_data(a, 2, 3), Ok(b"456".to_vec()));
+      // straddling the end: short read unless strict
+      let expected = if strict_bounds { Err(EINVAL) } else { Ok(b"67".to_vec()) };
+      assert_eq!(fs.read_data(a, 4, 4), expected);
+      assert_eq!(fs.read_data(a, 0, u32::MAX).is_err(), strict_bounds);
+      // entirely past the end is EOF in both modes
+      assert_eq!(fs.read_data(a, 6, 4), Ok(Vec::new()));
+      assert_eq!(fs.read_data(a, 100, 4), Ok(Vec::new()));
+    }
+    fs::remove_file(src).unwrap();
+  }
+
+  #[test]
+  fn negative_size() {
+    let src = source("negative-size", &[7; 1000]);
+    let configs = configs(&["name=a:size=-256", "name=b:offset=100:size=-256", "name=c:size=-2000", "name=d:offset=900:size=-200", "name=e:find=07:find_from=10:size=-1"]);
+    let options = FsOptions { timeout: Duration::ZERO, ..Default::default() };
+    let mut fs = RangeFs::new(Some(src.clone()), configs, options).unwrap();
+    let sizes = |fs: &mut RangeFs| ["a", "b", "c", "d", "e"].map(|name| {
+      let info = fs.update(ino(fs, name)).unwrap();
+      assert!(!info.err, "{}", name);
+      (info.offset, info.attr.size)
+    });
+    // trailer larger than the (rest of the) file clamps to 0
+    assert_eq!(sizes(&mut fs), [(0, 744), (100, 644), (0, 0), (900, 0), (10, 989)]);
+    // recomputed as the source grows
+    fs::write(&src, [7; 3000]).unwrap();
+    assert_eq!(sizes(&mut fs), [(0, 2744), (100, 2644), (0, 1000), (900, 1900), (10, 2989)]);
+    let info = &fs.inode_map[&ino(&fs, "c")];
+    assert_eq!(fs.read_inode(info, 990, 100).unwrap(), [7; 10]);
+    fs::remove_file(src).unwrap();
+  }
+
+  #[test]
+  fn source_truncated_during_reads() {
+    let src = source("truncated", &[1; 1000]);
+    let options = FsOptions { timeout: Duration::from_secs(3600), ..Default::default() };
+    let mut fs = RangeFs::new(Some(src.clone()), configs(&["name=a:offset=100:size=500"]), options).unwrap();
+    let a = ino(&fs, "a");
+    let info = &fs.inode_map[&a];
+    assert_eq!(fs.read_inode(info, 0, 500).unwrap().len(), 500);
+    let file = fs::OpenOptions::new().write(true).open(&src).unwrap();
+    // truncated after metadata is read: short reads of what's available
+    file.set_len(300).unwrap();
+    assert_eq!(fs.read_inode(info, 0, 500).unwrap().len(), 200);
+    assert_eq!(fs.read_inode(info, 150, 100).unwrap().len(), 50);
+    assert_eq!(fs.read_inode(info, 250, 100).unwrap(), b"");
+    // below the offset of the range: empty
+    file.set_len(50).unwrap();
+    assert_eq!(fs.read_inode(info, 0, 500).unwrap(), b"");
+    file.set_len(0).unwrap();
+    assert_eq!(fs.read_data(a, 10, 10), Ok(Vec::new()));
+    // metadata refreshed after the short read
+    assert_eq!(fs.inode_map[&a].attr.size, 0);
+    fs::remove_file(src).unwrap();
+  }
+
+  #[test]
+  fn kernel_settings_fall_back() {
+    // accepted as is
+    assert_eq!(negotiate("max_readahead", 128, Ok), Some(128));
+    // rejected with the nearest value accepted
+    let mut tried = Vec::new();
+    let value = negotiate("max_readahead", 1 << 30, |v| {
+      tried.push(v);
+      if v > 1 << 20 { Err(1 << 20) } else { Ok(v) }
+    });
+    assert_eq!(value, Some(1 << 20));
+    assert_eq!(tried, [1 << 30, 1 << 20]);
+    // rejected entirely: left at default
+    assert_eq!(negotiate("max_background", 64u16, |_| Err(16)), None);
+
+    assert!(enable_capability("writeback_cache", || Ok::<_, u32>(())));
+    // capability the config rejects (e.g. unsupported by an older kernel)
+    assert!(!enable_capability("writeback_cache", || Err(FUSE_WRITEBACK_CACHE)));
+  }
+
+  #[test]
+  fn reused_inodes_have_new_generations() {
+    let src = source("generation", b"0123456789");
+    let options = FsOptions { overlay: true, ..Default::default() };
+    let mut fs = RangeFs::new(Some(src.clone()), configs(&["name=a:size=3"]), options).unwrap();
+    let entry = |fs: &RangeFs, name| {
+      let info = &fs.inode_map[&ino(fs, name)];
+      (info.ino, info.generation)
+    };
+    // free the highest inode and create a file after it
+    let highest = *fs.inode_map.keys().max().unwrap();
+    let status = fs.file_map.iter().find_map(|(name, ino)| (*ino == highest).then(|| name.clone())).unwrap();
+    assert!(fs.remove(&status));
+    let created = fs.create_file(OsStr::new("x"), 0o644, 0, 0).map(|info| (info.ino, info.generation)).unwrap();
+    assert_ne!(created, (highest, 0));
+    assert!(fs.remove(OsStr::new("x")));
+    let recreated = fs.create_file(OsStr::new("x"), 0o644, 0, 0).map(|info| (info.ino, info.generation)).unwrap();
+    assert_ne!(recreated, created);
+
+    // numbers reused by a reload
+    let a = entry(&fs, "a");
+    fs.reloader().replace(configs(&["name=a:size=3"]), None).unwrap();
+    fs.apply_pending();
+    assert_eq!(ino(&fs, "a"), a.0);
+    assert_ne!(entry(&fs, "a"), a);
+    fs::remove_file(src).unwrap();
+  }
+
+  #[test]
+  fn scan_cache_keys_padding() {
+    let src = source("scan-pad", b"xxabcdef");
+    let fs = RangeFs::new(Some(src.clone()), configs(&["name=a:find=6162:size=100:pad=true", "name=b:find=6162:size=100"]), FsOptions::default()).unwrap();
+    // resolved by the same scan cache
+    assert_eq!(fs.inode_map[&ino(&fs, "a")].attr.size, 100);
+    assert_eq!(fs.inode_map[&ino(&fs, "b")].attr.size, 6);
+    fs::remove_file(src).unwrap();
+  }
+
+  #[test]
+  fn checksum_worker() {
+    let cache = Arc::new(ChecksumCache::default());
+    let state = (UNIX_EPOCH, 1, 0, 0);
+    let (tx, rx) = mpsc::channel();
+    // a panicking computation doesn't stop the worker
+    cache.spawn(Box::new(|| panic!("checksum")));
+    for i in 0..10 {
+      let (tx, worker) = (tx.clone(), cache.clone());
+      cache.spawn(Box::new(move || {
+        let line = worker.get_or_compute(2, state, || Ok(vec![i])).unwrap();
+        tx.send((line, thread::current().id())).unwrap();
+      }));
+    }
+    let results: Vec<_> = rx.iter().take(10).collect();
+    // computed once on a single thread and reused
+    assert!(results.iter().all(|r| *r == (vec![0], results[0].1)));
+    assert_eq!(cache.get(2, state), Some(vec![0]));
+    assert_eq!(cache.get(2, (UNIX_EPOCH, 2, 0, 0)), None);
+    // failures aren't cached
+    assert!(cache.get_or_compute(3, state, || Err(io::Error::other("x"))).is_err());
+    assert_eq!(cache.get_or_compute(3, state, || Ok(vec![1])).unwrap(), [1]);
+  }
+
+  #[test]
+  fn usage_with_overlay() {
+    let src = source("statfs-overlay", &[0; 2048]);
+    let fs = RangeFs::new(Some(src.clone()), configs(&["name=a:size=1024"]), FsOptions::default()).unwrap();
+    assert_eq!(fs.available(), (0, 0));
+    let options = FsOptions { overlay: true, ..Default::default() };
+    let mut fs = RangeFs::new(Some(src.clone()), configs(&["name=a:size=1024"]), options).unwrap();
+    let (free, ffree) = fs.available();
+    assert!(free > 0 && ffree > 0);
+    assert_eq!(fs.usage(), (2, 3));
+    // created file and extended mapped file in overlay
+    let x = fs.create_file(OsStr::new("x"), 0o644, 0, 0).unwrap().ino;
+    fs.overlays.get_mut(&x).unwrap().write(0, &[1; 1024], |_, _| Ok(Vec::new())).unwrap();
+    let a = ino(&fs, "a");
+    fs.overlays.insert(a, Overlay::new(1024));
+    fs.overlays.get_mut(&a).unwrap().write(1024, &[1; 512], |_, size| Ok(vec![0; size as usize])).unwrap();
+    assert_eq!(fs.usage(), (5, 4));
+    fs::remove_file(src).unwrap();
+  }
+
+  #[test]
+  fn lookups_kept_across_reload() {
+    let src = source("reload-lookups", b"0123456789");
+    let mut fs = RangeFs::new(Some(src.clone()), configs(&["name=a:size=3", "name=b:offset=3"]), FsOptions::default()).unwrap();
+    let (a, b) = (ino(&fs, "a"), ino(&fs, "b"));
+    fs.lookup_entry(FUSE_ROOT_ID, OsStr::new("a")).unwrap();
+    fs.lookup_entry(FUSE_ROOT_ID, OsStr::new("b")).unwrap();
+    fs.reloader().replace(configs(&["name=c:size=5"]), None).unwrap();
+    fs.apply_pending();
+    // a's number is reused by c
+    assert_eq!(ino(&fs, "c"), a);
+    fs.lookup_entry(FUSE_ROOT_ID, OsStr::new("c")).unwrap();
+    assert_eq!(fs.lookups.get(&a), Some(&2));
+    // forgetting the old inode leaves the new one referenced
+    fs.release(a, 1);
+    assert_eq!(fs.lookups.get(&a), Some(&1));
+    assert!(fs.remove(OsStr::new("c")));
+    assert!(fs.inode_map.contains_key(&a));
+    fs.release(a, 1);
+    assert!(!fs.inode_map.contains_key(&a));
+    // old inode only referenced by kernel expires by forget
+    assert_eq!(fs.lookups.get(&b), Some(&1));
+    fs.release(b, 1);
+    assert!(fs.lookups.is_empty());
+    fs::remove_file(src).unwrap();
+  }
 }
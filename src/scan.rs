@@ -0,0 +1,205 @@
+// Copyright (C) 2023-2024  DCsunset
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::{
+  io::{self, Read}, cmp,
+  collections::{HashMap, VecDeque},
+  path::PathBuf,
+  time::SystemTime,
+  sync::{Mutex, PoisonError}
+};
+use regex::bytes::Regex;
+
+/// Buffer size used when scanning source file
+const SCAN_BUF_SIZE: usize = 64 * 1024;
+/// Default max bytes to scan for regex (the scanned data is loaded into memory)
+const REGEX_SCAN_LIMIT: u64 = 64 * 1024 * 1024;
+
+/// Key of a scan: source files and scan parameters
+#[derive(Clone, PartialEq, Eq, Hash)]
+pub struct ScanKey {
+  pub sources: Vec<PathBuf>,
+  pub params: ScanParams
+}
+
+/// Config of a mapped file affecting the resolved range
+#[derive(Clone, PartialEq, Eq, Hash)]
+pub struct ScanParams {
+  pub lines: Option<(u64, Option<u64>)>,
+  pub regex_start: Option<String>,
+  pub regex_end: Option<String>,
+  pub find: Option<Vec<u8>>,
+  pub find_from: u64,
+  pub size: Option<u64>,
+  pub trailer: Option<u64>,
+  pub pad: bool,
+  pub max_scan: Option<u64>,
+  pub raid0: Option<u64>
+}
+
+/// Scan result valid for a specific version (mtime and size) of source
+struct ScanEntry {
+  mtime: SystemTime,
+  size: u64,
+  range: (u64, u64)
+}
+
+/// Bounded cache of resolved ranges shared by mappings
+/// (oldest entries are evicted first)
+pub struct ScanCache {
+  capacity: usize,
+  entries: Mutex<(HashMap<ScanKey, ScanEntry>, VecDeque<ScanKey>)>
+}
+
+impl ScanCache {
+  pub fn new(capacity: usize) -> Self {
+    Self {
+      capacity,
+      entries: Mutex::new((HashMap::new(), VecDeque::new()))
+    }
+  }
+
+  /// Get cached range if source is unchanged
+  pub fn get(&self, key: &ScanKey, mtime: SystemTime, size: u64) -> Option<(u64, u64)> {
+    let entries = self.entries.lock().unwrap_or_else(PoisonError::into_inner);
+    entries.0.get(key)
+      .filter(|e| e.mtime == mtime && e.size == size)
+      .map(|e| e.range)
+  }
+
+  pub fn insert(&self, key: ScanKey, mtime: SystemTime, size: u64, range: (u64, u64)) {
+    if self.capacity == 0 {
+      return;
+    }
+    let mut entries = self.entries.lock().unwrap_or_else(PoisonError::into_inner);
+    let (map, order) = &mut *entries;
+    let entry = ScanEntry { mtime, size, range };
+    // outdated entry of the same key is replaced
+    if map.insert(key.clone(), entry).is_none() {
+      order.push_back(key);
+      while order.len() > self.capacity {
+        if let Some(k) = order.pop_front() {
+          map.remove(&k);
+        }
+      }
+    }
+  }
+}
+
+/// Find the byte range of lines [start, end] (1-based, inclusive) in source.
+/// The whole remaining file is used if end is None.
+/// Lines past EOF are clamped to EOF.
+pub fn line_range(src: impl Read, src_size: u64, start: u64, end: Option<u64>, max_scan: Option<u64>) -> io::Result<(u64, u64)> {
+  let limit = cmp::min(src_size, max_scan.unwrap_or(u64::MAX));
+  let mut f = src.take(limit);
+  let mut buf = vec![0; SCAN_BUF_SIZE];
+  // current line number
+  let mut line = 1;
+  // offset of the start line
+  let mut begin = if start <= 1 { Some(0) } else { None };
+  let mut pos = 0;
+  loop {
+    if let (Some(b), None) = (begin, end) {
+      return Ok((b, src_size - b));
+    }
+    let n = f.read(&mut buf)?;
+    if n == 0 {
+      break;
+    }
+    for (i, _) in buf[..n].iter().enumerate().filter(|(_, c)| **c == b'\n') {
+      let next = pos + i as u64 + 1;
+      line += 1;
+      if line == start {
+        begin = Some(next);
+      }
+      if let (Some(b), Some(e)) = (begin, end) {
+        if line > e {
+          return Ok((b, next - b));
+        }
+      }
+    }
+    pos += n as u64;
+  }
+
+  if pos < src_size {
+    return Err(io::Error::other(format!("lines not found within scan limit of {} bytes", limit)));
+  }
+  let b = begin.unwrap_or(src_size);
+  Ok((b, src_size - b))
+}
+
+/// Find the byte range from the first match of start regex
+/// to the end of the first match of end regex after it.
+/// The range starts at the beginning of source if start is None
+/// and ends at the end of source if end is None.
+pub fn regex_range(src: impl Read, src_size: u64, start: Option<&Regex>, end: Option<&Regex>, max_scan: Option<u64>) -> io::Result<(u64, u64)> {
+  let limit = cmp::min(src_size, max_scan.unwrap_or(REGEX_SCAN_LIMIT));
+  let mut data = Vec::new();
+  src.take(limit).read_to_end(&mut data)?;
+  let not_found = |re: &Regex| {
+    if limit < src_size {
+      io::Error::other(format!("regex {} not found within scan limit of {} bytes", re, limit))
+    } else {
+      io::Error::other(format!("regex {} not found", re))
+    }
+  };
+
+  let (begin, search_from) = match start {
+    Some(re) => {
+      let m = re.find(&data).ok_or_else(|| not_found(re))?;
+      (m.start(), m.end())
+    },
+    None => (0, 0)
+  };
+  let begin = begin as u64;
+  match end {
+    Some(re) => {
+      let m = re.find_at(&data, search_from).ok_or_else(|| not_found(re))?;
+      Ok((begin, m.end() as u64 - begin))
+    },
+    None => Ok((begin, src_size - begin))
+  }
+}
+
+/// Find position of the first occurrence of signature in source starting from offset from
+pub fn find_signature(src: impl Read, src_size: u64, sig: &[u8], from: u64, max_scan: Option<u64>) -> io::Result<u64> {
+  let limit = cmp::min(src_size.saturating_sub(from), max_scan.unwrap_or(u64::MAX));
+  let mut f = src.take(limit);
+  let mut buf = vec![0; SCAN_BUF_SIZE];
+  // unmatched data kept for matches across chunks
+  let mut window: Vec<u8> = Vec::new();
+  // position of window in source
+  let mut pos = from;
+  loop {
+    let n = f.read(&mut buf)?;
+    if n == 0 {
+      break;
+    }
+    window.extend_from_slice(&buf[..n]);
+    if let Some(i) = window.windows(sig.len()).position(|w| w == sig) {
+      return Ok(pos + i as u64);
+    }
+    let keep = cmp::min(window.len(), sig.len() - 1);
+    pos += (window.len() - keep) as u64;
+    window.drain(..window.len() - keep);
+  }
+
+  let hex: String = sig.iter().map(|b| format!("{:02x}", b)).collect();
+  if from.saturating_add(limit) < src_size {
+    Err(io::Error::other(format!("signature {} not found within scan limit of {} bytes", hex, limit)))
+  } else {
+    Err(io::Error::other(format!("signature {} not found", hex)))
+  }
+}
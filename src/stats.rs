@@ -0,0 +1,225 @@
+// Copyright (C) 2023-2024  DCsunset
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::{
+  sync::{Arc, Mutex, PoisonError, atomic::{AtomicU64, Ordering}},
+  time::Duration, array, cmp, fs, io, thread,
+  path::{Path, PathBuf},
+  fmt::Write
+};
+use log::warn;
+
+/// Number of latency buckets
+const BUCKETS: usize = 40;
+
+/// Histogram of latency in microseconds with power-of-two buckets
+pub struct LatencyHistogram {
+  /// Bucket i counts latency in [2^(i-1), 2^i) us
+  buckets: [AtomicU64; BUCKETS],
+  count: AtomicU64
+}
+
+impl Default for LatencyHistogram {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+impl LatencyHistogram {
+  pub fn new() -> Self {
+    Self {
+      buckets: array::from_fn(|_| AtomicU64::new(0)),
+      count: AtomicU64::new(0)
+    }
+  }
+
+  pub fn record(&self, latency: Duration) {
+    let us = latency.as_micros().try_into().unwrap_or(u64::MAX);
+    let i = cmp::min((u64::BITS - us.leading_zeros()) as usize, BUCKETS - 1);
+    self.buckets[i].fetch_add(1, Ordering::Relaxed);
+    self.count.fetch_add(1, Ordering::Relaxed);
+  }
+
+  pub fn count(&self) -> u64 {
+    self.count.load(Ordering::Relaxed)
+  }
+
+  /// Upper bound of latency in us at percentile p (0-100)
+  pub fn percentile(&self, p: u64) -> u64 {
+    let target = (self.count() * p).div_ceil(100);
+    let mut acc = 0;
+    for (i, b) in self.buckets.iter().enumerate() {
+      acc += b.load(Ordering::Relaxed);
+      if acc >= target {
+        return 1 << i;
+      }
+    }
+    1 << (BUCKETS - 1)
+  }
+
+  /// Summary of count and percentiles
+  pub fn summary(&self) -> String {
+    format!(
+      "count={} p50={}us p90={}us p99={}us",
+      self.count(),
+      self.percentile(50),
+      self.percentile(90),
+      self.percentile(99)
+    )
+  }
+}
+
+/// Statistics of each mapped file
+pub struct InodeStats {
+  /// Latency of reading source
+  pub latency: LatencyHistogram,
+  /// Number of reads served
+  pub reads: AtomicU64,
+  /// Number of bytes served
+  pub bytes_read: AtomicU64,
+  /// Number of failed reads
+  pub errors: AtomicU64,
+  /// Size of cached content
+  pub cache_bytes: AtomicU64
+}
+
+impl Default for InodeStats {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+impl InodeStats {
+  pub fn new() -> Self {
+    Self {
+      latency: LatencyHistogram::new(),
+      reads: AtomicU64::new(0),
+      bytes_read: AtomicU64::new(0),
+      errors: AtomicU64::new(0),
+      cache_bytes: AtomicU64::new(0)
+    }
+  }
+
+  /// Record a successful read
+  pub fn record_read(&self, bytes: usize) {
+    self.reads.fetch_add(1, Ordering::Relaxed);
+    self.bytes_read.fetch_add(bytes as u64, Ordering::Relaxed);
+  }
+
+  /// Record a failed read
+  pub fn record_error(&self) {
+    self.errors.fetch_add(1, Ordering::Relaxed);
+  }
+}
+
+/// Stats of mapped files by name (shared with the metrics writer and updated when mappings change)
+pub type FileStats = Arc<Mutex<Vec<(String, Arc<InodeStats>)>>>;
+
+/// Metric name, type, help and getter
+type Metric = (&'static str, &'static str, &'static str, fn(&InodeStats) -> u64);
+
+/// Render metrics of mapped files in Prometheus text format
+pub fn prometheus_metrics(files: &[(String, Arc<InodeStats>)]) -> String {
+  let metrics: [Metric; 4] = [
+    ("rangefs_reads_total", "counter", "Number of reads served", |s| s.reads.load(Ordering::Relaxed)),
+    ("rangefs_bytes_total", "counter", "Number of bytes served", |s| s.bytes_read.load(Ordering::Relaxed)),
+    ("rangefs_errors_total", "counter", "Number of failed reads", |s| s.errors.load(Ordering::Relaxed)),
+    ("rangefs_cache_bytes", "gauge", "Size of cached content in bytes", |s| s.cache_bytes.load(Ordering::Relaxed))
+  ];
+  let mut out = String::new();
+  for (name, kind, help, value) in metrics {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+    for (file, stats) in files {
+      let _ = writeln!(out, "{}{{file={}}} {}", name, label_value(file), value(stats));
+    }
+  }
+  out
+}
+
+/// Quote label value in Prometheus text format (only backslash, double quote and line feed are escaped)
+fn label_value(s: &str) -> String {
+  let mut out = String::with_capacity(s.len() + 2);
+  out.push('"');
+  for c in s.chars() {
+    match c {
+      '"' => out.push_str("\\\""),
+      '\\' => out.push_str("\\\\"),
+      '\n' => out.push_str("\\n"),
+      c => out.push(c)
+    };
+  }
+  out.push('"');
+  out
+}
+
+/// Write metrics to path atomically (write to a temp file and rename)
+pub fn write_metrics(path: &Path, files: &[(String, Arc<InodeStats>)]) -> io::Result<()> {
+  let mut tmp = path.as_os_str().to_owned();
+  tmp.push(".tmp");
+  fs::write(&tmp, prometheus_metrics(files))?;
+  fs::rename(&tmp, path)
+}
+
+/// Spawn a thread writing metrics periodically
+pub fn spawn_metrics_writer(path: PathBuf, interval: Duration, files: FileStats) {
+  thread::spawn(move || loop {
+    let current = files.lock().unwrap_or_else(PoisonError::into_inner).clone();
+    if let Err(err) = write_metrics(&path, &current) {
+      warn!("Error writing metrics to {:?}: {}", path, err);
+    }
+    thread::sleep(interval);
+  });
+}
+
+/// Escape string as JSON string literal
+pub fn json_string(s: &str) -> String {
+  let mut out = String::with_capacity(s.len() + 2);
+  out.push('"');
+  for c in s.chars() {
+    match c {
+      '"' => out.push_str("\\\""),
+      '\\' => out.push_str("\\\\"),
+      '\n' => out.push_str("\\n"),
+      c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+      c => out.push(c)
+    };
+  }
+  out.push('"');
+  out
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn label_escapes() {
+    assert_eq!(label_value("a\"b\\c\nd"), r#""a\"b\\c\nd""#);
+    // other control characters are kept as is unlike JSON
+    assert_eq!(label_value("a\tb"), "\"a\tb\"");
+  }
+
+  #[test]
+  fn metrics_per_file() {
+    let stats = Arc::new(InodeStats::new());
+    stats.record_read(10);
+    stats.record_read(5);
+    let out = prometheus_metrics(&[("x\"y".into(), stats)]);
+    assert!(out.contains("rangefs_reads_total{file=\"x\\\"y\"} 2\n"));
+    assert!(out.contains("rangefs_bytes_total{file=\"x\\\"y\"} 15\n"));
+    assert!(out.contains("# TYPE rangefs_cache_bytes gauge\n"));
+  }
+}
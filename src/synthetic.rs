@@ -0,0 +1,55 @@
+// Copyright (C) 2023-2024  DCsunset
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+/// Data generated without reading source
+#[derive(Clone)]
+pub enum Synthetic {
+  /// Repeated byte pattern
+  Pattern(Vec<u8>),
+  /// Zero bytes
+  Zero,
+  /// Pseudo-random bytes from seed
+  Random(u64)
+}
+
+impl Synthetic {
+  /// Generate data at offset
+  pub fn read(&self, offset: u64, size: usize) -> Vec<u8> {
+    match self {
+      Synthetic::Pattern(pattern) => {
+        let start = (offset % pattern.len() as u64) as usize;
+        pattern.iter().cycle().skip(start).take(size).copied().collect()
+      },
+      Synthetic::Zero => vec![0; size],
+      Synthetic::Random(seed) => {
+        // each 8-byte block is derived from its index so any offset can be read directly
+        let start = (offset % 8) as usize;
+        (offset / 8..)
+          .flat_map(|block| splitmix64(seed ^ block.wrapping_mul(0x9e3779b97f4a7c15)).to_le_bytes())
+          .skip(start)
+          .take(size)
+          .collect()
+      }
+    }
+  }
+}
+
+/// SplitMix64 mixing function
+fn splitmix64(x: u64) -> u64 {
+  let mut z = x.wrapping_add(0x9e3779b97f4a7c15);
+  z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+  z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+  z ^ (z >> 31)
+}
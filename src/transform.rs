@@ -0,0 +1,240 @@
+// Copyright (C) 2023-2024  DCsunset
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::{io, sync::Arc};
+#[cfg(feature = "xz")]
+use std::io::Read;
+
+/// Transform applied to the data of a mapped file
+pub trait Transform {
+  /// Transform the whole input (error if input is invalid)
+  fn apply(&self, data: &[u8]) -> io::Result<Vec<u8>>;
+
+  /// Size of output for input of given size (None if unknown without applying)
+  fn size(&self, _input_size: u64) -> Option<u64> {
+    None
+  }
+
+  /// Window of input needed for output at [offset, offset + size)
+  /// as (input offset, input size, position of the output in the transformed window)
+  /// (None if not seekable, i.e. the whole input must be transformed)
+  fn input_window(&self, _offset: u64, _size: u64) -> Option<(u64, u64, u64)> {
+    None
+  }
+}
+
+/// Transform that can be shared across threads
+pub type SharedTransform = Arc<dyn Transform + Send + Sync>;
+
+/// Create transform by name
+pub fn from_name(name: &str) -> Option<SharedTransform> {
+  match name {
+    "hex" => Some(Arc::new(Hex)),
+    "base64" => Some(Arc::new(Base64)),
+    #[cfg(feature = "zstd")]
+    "zstd" => Some(Arc::new(Zstd)),
+    #[cfg(feature = "xz")]
+    "xz" => Some(Arc::new(Xz)),
+    _ => None
+  }
+}
+
+/// Apply transforms in order
+pub fn apply_all(transforms: &[SharedTransform], data: Vec<u8>) -> io::Result<Vec<u8>> {
+  transforms.iter().try_fold(data, |data, t| t.apply(&data))
+}
+
+/// Size of output after applying transforms in order
+pub fn size_all(transforms: &[SharedTransform], input_size: u64) -> Option<u64> {
+  transforms.iter().try_fold(input_size, |size, t| t.size(size))
+}
+
+/// Whether output of transforms can be read at any offset without transforming the whole input
+pub fn seekable(transforms: &[SharedTransform]) -> bool {
+  transforms.iter().all(|t| t.input_window(0, 0).is_some())
+}
+
+/// Parse hex string into bytes
+pub fn parse_hex(s: &str) -> Option<Vec<u8>> {
+  if !s.len().is_multiple_of(2) {
+    return None;
+  }
+  (0..s.len()).step_by(2)
+    .map(|i| s.get(i..i+2).and_then(|b| u8::from_str_radix(b, 16).ok()))
+    .collect()
+}
+
+/// XOR data at position (offset in the range) with a repeating key
+pub fn xor_at(key: &[u8], pos: u64, data: &mut [u8]) {
+  let start = (pos % key.len() as u64) as usize;
+  for (b, k) in data.iter_mut().zip(key.iter().cycle().skip(start)) {
+    *b ^= k;
+  }
+}
+
+/// Lowercase hex encoding
+struct Hex;
+
+impl Transform for Hex {
+  fn apply(&self, data: &[u8]) -> io::Result<Vec<u8>> {
+    const DIGITS: &[u8; 16] = b"0123456789abcdef";
+    Ok(data.iter()
+      .flat_map(|b| [DIGITS[(b >> 4) as usize], DIGITS[(b & 0xf) as usize]])
+      .collect())
+  }
+
+  fn size(&self, input_size: u64) -> Option<u64> {
+    input_size.checked_mul(2)
+  }
+
+  fn input_window(&self, offset: u64, size: u64) -> Option<(u64, u64, u64)> {
+    // each input byte becomes 2 output bytes
+    let start = offset / 2;
+    let end = offset.checked_add(size)?.div_ceil(2);
+    Some((start, end - start, offset % 2))
+  }
+}
+
+/// Standard base64 encoding with padding
+struct Base64;
+
+impl Transform for Base64 {
+  fn apply(&self, data: &[u8]) -> io::Result<Vec<u8>> {
+    const CHARS: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut out = Vec::with_capacity(data.len().div_ceil(3) * 4);
+    for chunk in data.chunks(3) {
+      let n = chunk.iter().enumerate().fold(0u32, |n, (i, b)| n | (*b as u32) << (16 - 8 * i));
+      for i in 0..4 {
+        if i <= chunk.len() {
+          out.push(CHARS[(n >> (18 - 6 * i) & 0x3f) as usize]);
+        } else {
+          out.push(b'=');
+        }
+      }
+    }
+    Ok(out)
+  }
+
+  fn size(&self, input_size: u64) -> Option<u64> {
+    input_size.div_ceil(3).checked_mul(4)
+  }
+
+  fn input_window(&self, offset: u64, size: u64) -> Option<(u64, u64, u64)> {
+    // each 3-byte input block becomes a 4-byte output block
+    let start = offset / 4;
+    let end = offset.checked_add(size)?.div_ceil(4);
+    Some((start * 3, (end - start) * 3, offset % 4))
+  }
+}
+
+/// Decompress a standalone zstd frame
+#[cfg(feature = "zstd")]
+struct Zstd;
+
+#[cfg(feature = "zstd")]
+impl Transform for Zstd {
+  fn apply(&self, data: &[u8]) -> io::Result<Vec<u8>> {
+    zstd::stream::decode_all(data)
+  }
+}
+
+/// Decompress a standalone xz stream
+#[cfg(feature = "xz")]
+struct Xz;
+
+#[cfg(feature = "xz")]
+impl Transform for Xz {
+  fn apply(&self, data: &[u8]) -> io::Result<Vec<u8>> {
+    let mut out = Vec::new();
+    xz2::read::XzDecoder::new(data).read_to_end(&mut out)?;
+    Ok(out)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  #[cfg(any(feature = "zstd", feature = "xz"))]
+  use std::{io::Write, process::{Command, Stdio}, thread};
+
+  /// Compress data by the CLI of the codec
+  #[cfg(any(feature = "zstd", feature = "xz"))]
+  fn compress(cmd: &str, data: &[u8]) -> Vec<u8> {
+    let mut child = Command::new(cmd).arg("-c")
+      .stdin(Stdio::piped())
+      .stdout(Stdio::piped())
+      .spawn()
+      .unwrap_or_else(|err| panic!("error running {}: {}", cmd, err));
+    let mut stdin = child.stdin.take().unwrap();
+    let input = data.to_vec();
+    let writer = thread::spawn(move || stdin.write_all(&input));
+    let output = child.wait_with_output().unwrap();
+    writer.join().unwrap().unwrap();
+    assert!(output.status.success(), "{} failed", cmd);
+    output.stdout
+  }
+
+  /// Decompress output of the CLI and check corrupt input fails
+  #[cfg(any(feature = "zstd", feature = "xz"))]
+  fn round_trip(name: &str) {
+    let data: Vec<u8> = (0..100_000u32).flat_map(|i| (i % 1000).to_le_bytes()).collect();
+    let transform = from_name(name).unwrap();
+    let compressed = compress(name, &data);
+    assert_eq!(transform.apply(&compressed).unwrap(), data);
+    assert!(transform.apply(&compressed[..compressed.len() / 2]).is_err());
+    assert!(transform.apply(b"not compressed").is_err());
+    // whole region is decompressed into cache
+    assert!(!seekable(&[transform]));
+  }
+
+  #[test]
+  #[cfg(feature = "zstd")]
+  fn zstd_round_trip() {
+    round_trip("zstd");
+  }
+
+  #[test]
+  #[cfg(feature = "xz")]
+  fn xz_round_trip() {
+    round_trip("xz");
+  }
+
+  #[test]
+  fn codecs_require_features() {
+    assert_eq!(from_name("zstd").is_some(), cfg!(feature = "zstd"));
+    assert_eq!(from_name("xz").is_some(), cfg!(feature = "xz"));
+  }
+
+  #[test]
+  fn input_windows() {
+    let data: Vec<u8> = (0..=255).collect();
+    for name in ["hex", "base64"] {
+      let transform = from_name(name).unwrap();
+      let whole = transform.apply(&data).unwrap();
+      assert_eq!(transform.size(data.len() as u64), Some(whole.len() as u64));
+      for offset in 0..20 {
+        for size in 0..10 {
+          let (in_offset, in_size, pos) = transform.input_window(offset, size).unwrap();
+          let input = &data[in_offset as usize..(in_offset + in_size) as usize];
+          let output = transform.apply(input).unwrap();
+          let window = &output[pos as usize..pos as usize + size as usize];
+          assert_eq!(window, &whole[offset as usize..(offset + size) as usize], "{} at {} of {}", name, offset, size);
+        }
+      }
+      assert!(transform.input_window(u64::MAX, 1).is_none());
+    }
+    assert!(seekable(&[from_name("hex").unwrap(), from_name("base64").unwrap()]));
+  }
+}
@@ -0,0 +1,71 @@
+// Copyright (C) 2023-2024  DCsunset
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::{
+  collections::HashMap, fs, thread,
+  path::PathBuf,
+  sync::{Arc, Mutex, PoisonError},
+  time::{Duration, SystemTime}
+};
+use fuser::Notifier;
+use log::warn;
+
+/// Kernel poll handles waiting for changes of each inode
+pub type Pollers = Arc<Mutex<HashMap<u64, Vec<u64>>>>;
+
+/// Source files of each inode to watch (updated when mappings change)
+pub type WatchTargets = Arc<Mutex<Vec<(u64, Vec<PathBuf>)>>>;
+
+/// Total size and latest mtime of source files (None if any is missing)
+type SourceState = Option<(u64, SystemTime)>;
+
+/// Total size and latest mtime of source files
+fn source_state(paths: &[PathBuf]) -> SourceState {
+  paths.iter().try_fold((0, SystemTime::UNIX_EPOCH), |(size, mtime), p| {
+    let m = fs::metadata(p).ok()?;
+    Some((size + m.len(), mtime.max(m.modified().ok()?)))
+  })
+}
+
+/// Spawn a thread watching source files of each inode
+/// and waking up its pollers when the sources change
+pub fn spawn_watcher(notifier: Notifier, interval: Duration, targets: WatchTargets, pollers: Pollers) {
+  thread::spawn(move || {
+    // last state of sources of each inode
+    let mut states: HashMap<u64, (Vec<PathBuf>, SourceState)> = HashMap::new();
+    loop {
+      let current = targets.lock().unwrap_or_else(PoisonError::into_inner).clone();
+      // new or changed targets start from their current state
+      states.retain(|ino, (paths, _)| current.iter().any(|(i, p)| i == ino && p == paths));
+      for (ino, paths) in current.iter() {
+        states.entry(*ino).or_insert_with(|| (paths.clone(), source_state(paths)));
+      }
+      thread::sleep(interval);
+      for (ino, (paths, state)) in states.iter_mut() {
+        let new_state = source_state(paths);
+        if new_state == *state {
+          continue;
+        }
+        *state = new_state;
+        let handles = pollers.lock().unwrap_or_else(PoisonError::into_inner).remove(ino);
+        for kh in handles.into_iter().flatten() {
+          if let Err(err) = notifier.poll(kh) {
+            warn!("Error notifying poll handle of inode {}: {}", ino, err);
+          }
+        }
+      }
+    }
+  });
+}